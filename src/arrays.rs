@@ -0,0 +1,167 @@
+//! Arrays (`array NAME: u32 * LENGTH` in the `.DATA` section) are data labels with an element width and a length.
+//! `NAME[INDEX]` reads or writes an element, and the constant `NAME.len` is the length.
+
+use crate::ast::{AssignTarget, DataBlock, DataDef, Expr, FieldWidth};
+use crate::constants::ConstTable;
+use crate::error::{Error, IResult};
+use crate::intermediate_repr::{IntermediateBlock, IntermediateLine};
+
+use std::collections::HashMap;
+use std::mem;
+
+/// Name of the constant for the length of an array
+pub fn length_constant(name: &str) -> String {
+    format!("{}.len", name)
+}
+
+/// Maps array names to their element width and length
+pub struct ArrayTable<'a>(HashMap<&'a str, (FieldWidth, u32)>);
+
+/// Finds every array declared in the data sections.
+/// Lengths are read from constants, so this must run after constants::collect_constants
+pub fn collect_arrays<'a>(data: &[DataBlock<'a>], constants: &ConstTable<'a>) -> ArrayTable<'a> {
+    ArrayTable(
+        data.iter()
+            .flatten()
+            .filter_map(|data_def| match data_def {
+                DataDef::Array(name, width, _) => {
+                    let length = constants[length_constant(name).as_str()];
+                    Some((*name, (*width, length)))
+                }
+                _ => None,
+            })
+            .collect(),
+    )
+}
+
+impl<'a> ArrayTable<'a> {
+    /// The width and address of an element of an array
+    fn element_address(
+        &self,
+        name: &'a str,
+        index: Expr<'a>,
+        bounds_checks: bool,
+    ) -> IResult<(FieldWidth, Expr<'a>)> {
+        let &(width, length) = self
+            .0
+            .get(name)
+            .ok_or_else(|| Error::NotAnArray(name.to_string()))?;
+        let index = if bounds_checks {
+            Expr::BoundsCheck(Box::new(index), length, name)
+        } else {
+            index
+        };
+        let offset = match width {
+            FieldWidth::U8 => index,
+            FieldWidth::U32 => Expr::Mul(Box::new(index), Box::new(Expr::Literal(4))),
+        };
+        Ok((
+            width,
+            Expr::Add(Box::new(Expr::VarAddress(name)), Box::new(offset)),
+        ))
+    }
+}
+
+/// Replaces every array index with a memory access of the element's width.
+/// If bounds_checks is true, indexes are checked against the array length when the program runs
+pub fn lower_indexing<'a>(
+    mut code: IntermediateBlock<'a>,
+    arrays: &ArrayTable<'a>,
+    bounds_checks: bool,
+) -> IResult<IntermediateBlock<'a>> {
+    let lower = &mut |e: &mut Expr<'a>| {
+        if let Expr::Index(name, index) = e {
+            let index = mem::replace(&mut **index, Expr::Literal(0));
+            let (width, address) = arrays.element_address(name, index, bounds_checks)?;
+            *e = match width {
+                FieldWidth::U8 => Expr::DerefByte(Box::new(address)),
+                FieldWidth::U32 => Expr::Deref(Box::new(address)),
+            };
+        }
+        Ok(())
+    };
+
+    for line in &mut code {
+        match line {
+            IntermediateLine::Assign(target, e) => {
+                e.try_visit_mut(lower)?;
+                match target {
+                    AssignTarget::Index(name, index) => {
+                        index.try_visit_mut(lower)?;
+                        let index = mem::replace(index, Expr::Literal(0));
+                        let (width, address) =
+                            arrays.element_address(name, index, bounds_checks)?;
+                        *target = match width {
+                            FieldWidth::U8 => AssignTarget::ByteAddr(address),
+                            FieldWidth::U32 => AssignTarget::Addr(address),
+                        };
+                    }
+                    AssignTarget::Addr(addr) | AssignTarget::ByteAddr(addr) => {
+                        addr.try_visit_mut(lower)?
+                    }
+                    AssignTarget::Var(_) | AssignTarget::Field(..) => {}
+                }
+            }
+            IntermediateLine::JumpFalse(e, _) | IntermediateLine::Expr(e) => {
+                e.try_visit_mut(lower)?
+            }
+            _ => {}
+        }
+    }
+    Ok(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::intermediate_repr::{display_intermediate_block, DataSegment};
+    use crate::lower_source;
+
+    const ARRAYS: &str =
+        ".DATA\nconst N = 3\narray words: u32 * N * 2\narray bytes: u8 * 4\n.CODE\n";
+
+    /// The IR of code that can use the arrays `words` (6 u32s) and `bytes` (4 u8s)
+    fn lowered(code: &str, bounds_checks: bool) -> String {
+        let source = format!("{}{}", ARRAYS, code);
+        let (ir, _) = lower_source(&source, bounds_checks).unwrap();
+        display_intermediate_block(&ir)
+    }
+
+    #[test]
+    fn arrays_are_zeroed_data() {
+        let source = format!("{}exit()", ARRAYS);
+        let (_, DataSegment(bytes, labels)) = lower_source(&source, false).unwrap();
+        assert_eq!(bytes, vec![0; 28]);
+        assert_eq!((labels["words"], labels["bytes"]), (0, 24));
+    }
+
+    #[test]
+    fn length_is_a_constant() {
+        assert_eq!(
+            lowered("numprintln(words.len + bytes.len)", false),
+            lowered("numprintln(6 + 4)", false)
+        );
+    }
+
+    #[test]
+    fn indexes_are_scaled_by_element_width() {
+        assert_eq!(
+            lowered("words[i] -> bytes[i + 1]", false),
+            lowered("{&words + i * 4} -> [&bytes + (i + 1)]", false)
+        );
+    }
+
+    #[test]
+    fn bounds_checks_wrap_the_index() {
+        assert_eq!(
+            lowered("words[i] -> x", true),
+            "Deref(Add(VarAddress(\"words\"), Mul(BoundsCheck(Var(\"i\"), 6, \"words\"), Literal(4)))) -> Var(\"x\")\n"
+        );
+    }
+
+    #[test]
+    fn indexing_a_label_that_is_not_an_array() {
+        let result = lower_source(".DATA\nd:\n0\n.CODE\nd[0] -> x", false);
+        assert!(matches!(result, Err(Error::NotAnArray(name)) if name == "d"));
+    }
+}
@@ -1,12 +1,25 @@
 //! AST definitions used by PEG and interpreter
 
+use std::borrow::Cow;
+
 pub type Block<'a> = Vec<LineData<'a>>;
 
 pub type DataBlock<'a> = Vec<DataDef<'a>>;
 
+/// A `.DATA` or `.CODE` section of a program. A program can have any number of each, in any order.
+#[derive(Debug)]
+pub enum Section<'a> {
+    Data(DataBlock<'a>),
+    Code(Block<'a>),
+    /// Path of another file whose sections are added to the program
+    Include(&'a str),
+    /// Name of a standard library whose sections are added to the program
+    Import(&'a str),
+}
+
 type E<'a> = Box<Expr<'a>>;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Expr<'a> {
     Literal(u32),
     Deref(E<'a>),
@@ -30,10 +43,76 @@ pub enum Expr<'a> {
     BitXor(E<'a>, E<'a>),
     Shl(E<'a>, E<'a>),
     Shr(E<'a>, E<'a>),
-    FunCall(&'a str, Vec<Expr<'a>>),
+    /// Name is owned once a call inside a module is resolved to the module's function
+    FunCall(Cow<'a, str>, Vec<Expr<'a>>),
+    /// `base.field`: the offset of a field if base is a struct, otherwise the field of the
+    /// struct that the variable base points to
+    Field(&'a str, &'a str),
+    /// `array[index]`, an element of an array declared in the data section
+    Index(&'a str, E<'a>),
+    /// index, array length, array name: evaluates to the index, or errors if it is not less than the length
+    BoundsCheck(E<'a>, u32, &'a str),
 }
 
-#[derive(Debug)]
+impl<'a> Expr<'a> {
+    /// Calls f on this expression and every subexpression, parents before children
+    pub fn visit(&self, f: &mut impl FnMut(&Expr<'a>)) {
+        use Expr::*;
+        f(self);
+        match self {
+            Literal(_) | Var(_) | VarAddress(_) | Field(..) => {}
+            Deref(e) | DerefByte(e) | Invert(e) | Index(_, e) | BoundsCheck(e, ..) => e.visit(f),
+            Add(l, r) | Sub(l, r) | Mul(l, r) | Div(l, r) | Mod(l, r) | Lt(l, r) | Gt(l, r)
+            | Leq(l, r) | Geq(l, r) | Eq(l, r) | Neq(l, r) | BitAnd(l, r) | BitOr(l, r)
+            | BitXor(l, r) | Shl(l, r) | Shr(l, r) => {
+                l.visit(f);
+                r.visit(f);
+            }
+            FunCall(_, args) => {
+                for arg in args {
+                    arg.visit(f);
+                }
+            }
+        }
+    }
+
+    /// Calls f on every subexpression and then this expression (children before parents)
+    pub fn visit_mut(&mut self, f: &mut impl FnMut(&mut Expr<'a>)) {
+        let _: Result<(), ()> = self.try_visit_mut(&mut |e| {
+            f(e);
+            Ok(())
+        });
+    }
+
+    /// Calls f on every subexpression and then this expression (children before parents),
+    /// stopping at the first error
+    pub fn try_visit_mut<E>(
+        &mut self,
+        f: &mut impl FnMut(&mut Expr<'a>) -> Result<(), E>,
+    ) -> Result<(), E> {
+        use Expr::*;
+        match self {
+            Literal(_) | Var(_) | VarAddress(_) | Field(..) => {}
+            Deref(e) | DerefByte(e) | Invert(e) | Index(_, e) | BoundsCheck(e, ..) => {
+                e.try_visit_mut(f)?
+            }
+            Add(l, r) | Sub(l, r) | Mul(l, r) | Div(l, r) | Mod(l, r) | Lt(l, r) | Gt(l, r)
+            | Leq(l, r) | Geq(l, r) | Eq(l, r) | Neq(l, r) | BitAnd(l, r) | BitOr(l, r)
+            | BitXor(l, r) | Shl(l, r) | Shr(l, r) => {
+                l.try_visit_mut(f)?;
+                r.try_visit_mut(f)?;
+            }
+            FunCall(_, args) => {
+                for arg in args {
+                    arg.try_visit_mut(f)?;
+                }
+            }
+        }
+        f(self)
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct LineData<'a> {
     /// The character index into the input string that this line starts on
     pub start_index: usize,
@@ -46,7 +125,7 @@ impl<'a> From<(usize, Line<'a>)> for LineData<'a> {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Line<'a> {
     Assign(AssignTarget<'a>, Expr<'a>),
     For(&'a str, Expr<'a>, Expr<'a>, Block<'a>),
@@ -56,6 +135,14 @@ pub enum Line<'a> {
     Label(&'a str),
     // name, arg names, is_saveargs
     FunDeclaration(&'a str, Vec<&'a str>, Block<'a>, bool),
+    VarDeclaration(Vec<&'a str>),
+    Const(&'a str, Expr<'a>),
+    /// Functions and labels in the block are prefixed with `name.`
+    Module(&'a str, Block<'a>),
+    // name, parameter names, body
+    Macro(&'a str, Vec<&'a str>, Block<'a>),
+    MacroCall(&'a str, Vec<Expr<'a>>),
+    Struct(&'a str, Vec<StructField<'a>>),
     // at the moment, the Line::Expr can only be a Expr::FunCall, otherwise
     // ambiguity arises (eg. 'end' getting parsed as Expr::Var("end") instead
     // of the end of a block)
@@ -63,15 +150,55 @@ pub enum Line<'a> {
 }
 
 /// The target of an assignment expression (ie. storing to a variable or an address)
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum AssignTarget<'a> {
     Var(&'a str),
     Addr(Expr<'a>),
     ByteAddr(Expr<'a>),
+    /// `var.field`, a field of the struct that var points to
+    Field(&'a str, &'a str),
+    /// `array[index]`
+    Index(&'a str, Expr<'a>),
+}
+
+/// A field of a struct declaration
+#[derive(Debug, Clone)]
+pub struct StructField<'a> {
+    pub name: &'a str,
+    pub width: FieldWidth,
+}
+
+/// Width of a struct field or array element
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FieldWidth {
+    U8,
+    U32,
+}
+
+impl FieldWidth {
+    /// Size in bytes
+    pub fn size(self) -> u32 {
+        match self {
+            FieldWidth::U8 => 1,
+            FieldWidth::U32 => 4,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum DataDef<'a> {
     Label(&'a str),
     Bytes(Vec<u8>),
+    /// u32s computed from constants and the addresses of data labels
+    U32s(Vec<Expr<'a>>),
+    /// A number of zero bytes computed from constants
+    Zeros(Expr<'a>),
+    /// Repeat the data a number of times computed from constants
+    Repeat(Expr<'a>, Box<DataDef<'a>>),
+    /// Zero bytes up to the next multiple of a number computed from constants
+    Align(Expr<'a>),
+    Const(&'a str, Expr<'a>),
+    Struct(&'a str, Vec<StructField<'a>>),
+    /// name, element width, length computed from constants
+    Array(&'a str, FieldWidth, Expr<'a>),
 }
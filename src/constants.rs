@@ -0,0 +1,246 @@
+//! Compile time constants (`const NAME = EXPR`).
+//! Constants are evaluated when the program is loaded, and substituted into the code as literals.
+//! Struct declarations also define constants (see structs).
+
+use crate::arrays::length_constant;
+use crate::ast::{AssignTarget, DataBlock, DataDef, Expr};
+use crate::error::{Error, IResult};
+use crate::intermediate_repr::{IntermediateBlock, IntermediateBlockSlice, IntermediateLine};
+use crate::interpreter::memory::CONSTANT_NAMES;
+use crate::structs::StructTable;
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+/// Maps constant names to their values. Names are owned for constants defined by structs (eg. `NAME.size`)
+pub type ConstTable<'a> = HashMap<Cow<'a, str>, u32>;
+
+/// Evaluates every constant declared in the data sections and then the code, in order.
+/// A constant can only use constants declared before it, or constants defined by structs.
+/// Array lengths are also evaluated here, as the `NAME.len` constants.
+/// A constant can not have the name of a memory layout constant or a data label.
+pub fn collect_constants<'a>(
+    data: &[DataBlock<'a>],
+    code: &IntermediateBlockSlice<'a>,
+    structs: &StructTable<'a>,
+) -> IResult<ConstTable<'a>> {
+    let data_constants = data.iter().flatten().filter_map(|data_def| match data_def {
+        DataDef::Const(name, e) => Some((Cow::from(*name), e)),
+        DataDef::Array(name, _, length) => Some((Cow::from(length_constant(name)), length)),
+        _ => None,
+    });
+    let code_constants = code.iter().filter_map(|line| match line {
+        IntermediateLine::Const(name, e) => Some((Cow::from(*name), e)),
+        _ => None,
+    });
+
+    let data_labels: Vec<&str> = data
+        .iter()
+        .flatten()
+        .filter_map(|data_def| match data_def {
+            DataDef::Label(name) | DataDef::Array(name, ..) => Some(*name),
+            _ => None,
+        })
+        .collect();
+
+    let mut constants: ConstTable = structs
+        .constants()
+        .into_iter()
+        .map(|(name, value)| (Cow::from(name), value))
+        .collect();
+    for (name, e) in data_constants.chain(code_constants) {
+        let value = eval_const_expr(e, &constants)?;
+        if constants.contains_key(&name)
+            || CONSTANT_NAMES.contains(&&*name)
+            || data_labels.contains(&&*name)
+        {
+            return Err(Error::ConstantRedefinition(name.into_owned()));
+        }
+        constants.insert(name, value);
+    }
+    Ok(constants)
+}
+
+/// Replaces every use of a constant in the code with its value, and removes the const declarations
+pub fn substitute_constants<'a>(
+    code: IntermediateBlock<'a>,
+    constants: &ConstTable<'a>,
+) -> IResult<IntermediateBlock<'a>> {
+    let substitute = &mut |e: &mut Expr<'a>| match e {
+        Expr::Var(name) => {
+            if let Some(&value) = constants.get(*name) {
+                *e = Expr::Literal(value);
+            }
+            Ok(())
+        }
+        Expr::Field(base, field) => {
+            if let Some(&value) = constants.get(format!("{}.{}", base, field).as_str()) {
+                *e = Expr::Literal(value);
+            }
+            Ok(())
+        }
+        Expr::VarAddress(name) if constants.contains_key(*name) => {
+            Err(Error::ConstantHasNoAddress(name.to_string()))
+        }
+        _ => Ok(()),
+    };
+    let check_assignable = |name: &str| {
+        if constants.contains_key(name) {
+            Err(Error::AssignToConstant(name.to_string()))
+        } else {
+            Ok(())
+        }
+    };
+
+    let mut block = Vec::with_capacity(code.len());
+    for mut line in code {
+        match &mut line {
+            IntermediateLine::Const(..) => continue,
+            // removed by structs::lower_field_access, which needs them until then
+            IntermediateLine::Struct(..) => {}
+            IntermediateLine::Assign(target, e) => {
+                e.try_visit_mut(substitute)?;
+                match target {
+                    AssignTarget::Var(name) => check_assignable(name)?,
+                    AssignTarget::Field(base, field) => {
+                        check_assignable(&format!("{}.{}", base, field))?
+                    }
+                    AssignTarget::Addr(addr)
+                    | AssignTarget::ByteAddr(addr)
+                    | AssignTarget::Index(_, addr) => addr.try_visit_mut(substitute)?,
+                }
+            }
+            IntermediateLine::JumpFalse(e, _) | IntermediateLine::Expr(e) => {
+                e.try_visit_mut(substitute)?
+            }
+            IntermediateLine::FunDeclaration(_, params, _) => {
+                for param in params {
+                    check_assignable(param)?;
+                }
+            }
+            IntermediateLine::VarDeclaration(names) => {
+                if let Some(name) = names.iter().find(|name| constants.contains_key(**name)) {
+                    return Err(Error::ConstantRedefinition(name.to_string()));
+                }
+            }
+            IntermediateLine::Goto(_) | IntermediateLine::Label(_) | IntermediateLine::FunReturn => {}
+        }
+        block.push(line);
+    }
+    Ok(block)
+}
+
+/// Evaluates an expression made only of literals, constants and operators
+pub fn eval_const_expr(expr: &Expr, constants: &ConstTable) -> IResult<u32> {
+    use Expr::*;
+    let bin_op = |l: &Expr, r: &Expr, operation: fn(u32, u32) -> u32| -> IResult<u32> {
+        Ok(operation(
+            eval_const_expr(l, constants)?,
+            eval_const_expr(r, constants)?,
+        ))
+    };
+    // returns a bool as 1 or 0
+    let bin_bool_op = |l: &Expr, r: &Expr, operation: fn(u32, u32) -> bool| -> IResult<u32> {
+        Ok(operation(
+            eval_const_expr(l, constants)?,
+            eval_const_expr(r, constants)?,
+        ) as u32)
+    };
+
+    match expr {
+        Literal(n) => Ok(*n),
+        Var(name) => constants
+            .get(*name)
+            .copied()
+            .ok_or_else(|| Error::NotConstant(format!("variable `{}`", name))),
+        Field(base, field) => constants
+            .get(format!("{}.{}", base, field).as_str())
+            .copied()
+            .ok_or_else(|| Error::NotConstant(format!("field `{}.{}`", base, field))),
+        Add(l, r) => bin_op(l, r, u32::wrapping_add),
+        Sub(l, r) => bin_op(l, r, u32::wrapping_sub),
+        Mul(l, r) => bin_op(l, r, u32::wrapping_mul),
+        Div(l, r) | Mod(l, r) => {
+            let l = eval_const_expr(l, constants)?;
+            let r = eval_const_expr(r, constants)?;
+            if r == 0 {
+                Err(Error::DivisionByZero)
+            } else if let Div(..) = expr {
+                Ok(l / r)
+            } else {
+                Ok(l % r)
+            }
+        }
+        BitAnd(l, r) => bin_op(l, r, |a, b| a & b),
+        BitOr(l, r) => bin_op(l, r, |a, b| a | b),
+        BitXor(l, r) => bin_op(l, r, |a, b| a ^ b),
+        Shl(l, r) => bin_op(l, r, u32::wrapping_shl),
+        Shr(l, r) => bin_op(l, r, u32::wrapping_shr),
+        Lt(l, r) => bin_bool_op(l, r, |a, b| a < b),
+        Gt(l, r) => bin_bool_op(l, r, |a, b| a > b),
+        Leq(l, r) => bin_bool_op(l, r, |a, b| a <= b),
+        Geq(l, r) => bin_bool_op(l, r, |a, b| a >= b),
+        Eq(l, r) => bin_bool_op(l, r, |a, b| a == b),
+        Neq(l, r) => bin_bool_op(l, r, |a, b| a != b),
+        Invert(e) => Ok((eval_const_expr(e, constants)? == 0) as u32),
+        VarAddress(name) => Err(Error::NotConstant(format!("`&{}`", name))),
+        Deref(_) | DerefByte(_) => Err(Error::NotConstant(String::from("memory access"))),
+        FunCall(name, _) => Err(Error::NotConstant(format!("call to `{}`", name))),
+        Index(name, _) => Err(Error::NotConstant(format!("element of `{}`", name))),
+        BoundsCheck(index, length, array) => {
+            let index = eval_const_expr(index, constants)?;
+            if index < *length {
+                Ok(index)
+            } else {
+                Err(Error::IndexOutOfBounds {
+                    array: array.to_string(),
+                    index,
+                    length: *length,
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::lower_source;
+
+    fn redefined(source: &str) -> String {
+        match lower_source(source, false) {
+            Err(Error::ConstantRedefinition(name)) => name,
+            Err(e) => panic!("expected a redefinition, got {}", e),
+            Ok(_) => panic!("expected a redefinition"),
+        }
+    }
+
+    #[test]
+    fn constants_can_not_be_defined_twice() {
+        assert_eq!(redefined(".DATA\nconst A = 1\n.CODE\nconst A = 2"), "A");
+    }
+
+    #[test]
+    fn constants_can_not_shadow_memory_constants() {
+        assert_eq!(
+            redefined(".DATA\nconst HEAP_START = 0\n.CODE\n"),
+            "HEAP_START"
+        );
+        assert_eq!(
+            redefined(".DATA\nd:\n0\n.CODE\nconst MEMORY_SIZE = 1"),
+            "MEMORY_SIZE"
+        );
+    }
+
+    #[test]
+    fn constants_can_not_shadow_data_labels() {
+        assert_eq!(
+            redefined(".DATA\nlabel:\n0\n.CODE\nconst label = 1"),
+            "label"
+        );
+        assert_eq!(
+            redefined(".DATA\nconst list = 1\narray list: u8 * 4\n.CODE\n"),
+            "list"
+        );
+    }
+}
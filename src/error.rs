@@ -7,10 +7,19 @@ pub type IResult<T> = Result<T, Error>;
 pub enum Error {
     Graphics(String),
     System(String),
+    FileNotReadable(String),
+    Parse {
+        file: String,
+        message: String,
+    },
+    /// The chain of files that include each other, starting and ending with the same file
+    IncludeCycle(Vec<String>),
+    LibraryNotFound(String),
     LabelNotFound(String),
     FunctionNotFound(String),
     NameIsNotFunction(String),
     LabelRedefinition(String),
+    DataLabelRedefinition(String),
     U32OutOfBounds {
         u32_read_index: usize,
         memory_length: usize,
@@ -31,7 +40,52 @@ pub enum Error {
     },
     InvalidCharacterValue(u32),
     InvalidScancode(u32),
-    InvalidSpriteIndex(u32)
+    InvalidSpriteIndex(u32),
+    SpriteFreed(u32),
+    InvalidTargetIndex(u32),
+    OverlappingCopy {
+        dst: usize,
+        src: usize,
+        len: usize,
+    },
+    AssignToConstant(String),
+    ConstantHasNoAddress(String),
+    UndeclaredVariable(String),
+    ConstantRedefinition(String),
+    NotConstant(String),
+    DivisionByZero,
+    MemoryTooSmall {
+        required: usize,
+        available: usize,
+    },
+    MemoryTooLarge,
+    MacroNotFound(String),
+    MacroRedefinition(String),
+    MacroArgumentMismatch {
+        expected: usize,
+        got: usize,
+        macro_name: String,
+    },
+    /// A macro parameter used as a variable name (eg. assigned to) was given an expression
+    MacroArgumentNotVariable {
+        macro_name: String,
+        param: String,
+    },
+    MacroRecursionLimit(String),
+    StructRedefinition(String),
+    FieldRedefinition {
+        struct_name: String,
+        field: String,
+    },
+    FieldNotFound(String),
+    /// Structs have fields with this name at different offsets or widths
+    AmbiguousField(String),
+    NotAnArray(String),
+    IndexOutOfBounds {
+        array: String,
+        index: u32,
+        length: u32,
+    },
 }
 
 impl Display for Error {
@@ -40,6 +94,10 @@ impl Display for Error {
         match self {
             Graphics(s) => write!(f, "graphics error {}", s),
             System(s) => write!(f, "system error {}", s),
+            FileNotReadable(s) => write!(f, "could not read file `{}`", s),
+            Parse { file, message } => write!(f, "{}: {}", file, message),
+            IncludeCycle(files) => write!(f, "files include each other: {}", files.join(" -> ")),
+            LibraryNotFound(s) => write!(f, "there is no standard library named `{}`", s),
             LabelNotFound(s) => write!(f, "could not find label `{}`", s),
             FunctionNotFound(s) => write!(f, "could not find function {}", s),
             NameIsNotFunction(s) => write!(
@@ -48,6 +106,7 @@ impl Display for Error {
                 s
             ),
             LabelRedefinition(s) => write!(f, "label `{}` is defined twice", s),
+            DataLabelRedefinition(s) => write!(f, "data label `{}` is defined twice", s),
             U32OutOfBounds {
                 u32_read_index,
                 memory_length,
@@ -91,6 +150,106 @@ impl Display for Error {
             InvalidCharacterValue(c) => write!(f, "invalid character value 0x{:X}", c),
             InvalidScancode(s) => write!(f, "invalid scancode: {}", s),
             InvalidSpriteIndex(i) => write!(f, "invalid sprite index: {}", i),
+            SpriteFreed(i) => write!(f, "sprite {} is used after it was freed", i),
+            InvalidTargetIndex(i) => write!(f, "invalid target index: {}", i),
+            OverlappingCopy { dst, src, len } => write!(
+                f,
+                "memcpy of {} bytes from {} to {} overlaps, use memmove instead",
+                len, src, dst
+            ),
+            AssignToConstant(s) => write!(f, "cannot assign to constant `{}`", s),
+            ConstantHasNoAddress(s) => write!(f, "cannot take the address of constant `{}`", s),
+            UndeclaredVariable(s) => write!(f, "variable `{}` is used but never declared", s),
+            ConstantRedefinition(s) => write!(f, "constant `{}` is defined twice", s),
+            NotConstant(s) => write!(f, "{} can not be used in a constant expression", s),
+            DivisionByZero => write!(f, "division by zero in a constant expression"),
+            MemoryTooSmall {
+                required,
+                available,
+            } => write!(
+                f,
+                "program needs at least {} bytes of memory, but only {} are available",
+                required, available
+            ),
+            MemoryTooLarge => write!(
+                f,
+                "program needs more than the {} bytes of memory that u32 addresses can reach",
+                crate::interpreter::memory::MAX_MEMORY_SIZE
+            ),
+            MacroNotFound(s) => write!(f, "could not find macro `{}`", s),
+            MacroRedefinition(s) => write!(f, "macro `{}` is defined twice", s),
+            MacroArgumentMismatch {
+                expected,
+                got,
+                macro_name,
+            } => write!(
+                f,
+                "macro `{}` expects {} arguments but {} were supplied",
+                macro_name, expected, got
+            ),
+            MacroArgumentNotVariable { macro_name, param } => write!(
+                f,
+                "parameter `{}` of macro `{}` is used as a variable, so its argument must be a variable name",
+                param, macro_name
+            ),
+            MacroRecursionLimit(s) => write!(
+                f,
+                "macro `{}` expands itself too many times (is it recursive?)",
+                s
+            ),
+            StructRedefinition(s) => write!(f, "struct `{}` is defined twice", s),
+            FieldRedefinition { struct_name, field } => write!(
+                f,
+                "struct `{}` can not have a field named `{}` (it is used twice, or is `size`)",
+                struct_name, field
+            ),
+            FieldNotFound(s) => write!(f, "no struct has a field named `{}`", s),
+            NotAnArray(s) => write!(f, "`{}` is indexed, but is not an array", s),
+            IndexOutOfBounds {
+                array,
+                index,
+                length,
+            } => write!(
+                f,
+                "index {} is out of bounds for array `{}` of length {}",
+                index, array, length
+            ),
+            AmbiguousField(s) => write!(
+                f,
+                "structs have different fields named `{}`, use {{ptr + STRUCT.{}}} instead",
+                s, s
+            ),
         }
     }
-}
\ No newline at end of file
+}
+
+/// Problems that do not stop a program from running, but are probably mistakes
+#[derive(Debug)]
+pub enum Warning {
+    VarNeverWritten(String),
+    /// The same name is used in the data/variable namespace and the code namespace.
+    /// Kinds are descriptions of what the name is in each namespace, eg. "data label" and "function"
+    NameShadowed {
+        name: String,
+        data_kind: &'static str,
+        code_kind: &'static str,
+    },
+}
+
+impl Display for Warning {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        use Warning::*;
+        match self {
+            VarNeverWritten(s) => write!(f, "variable `{}` is read but never written", s),
+            NameShadowed {
+                name,
+                data_kind,
+                code_kind,
+            } => write!(
+                f,
+                "`{}` is the name of both a {} and a {}",
+                name, data_kind, code_kind
+            ),
+        }
+    }
+}
@@ -0,0 +1,176 @@
+//! A built in 3x5 pixel font for drawing text
+
+/// Width of a glyph in pixels
+const GLYPH_WIDTH: u32 = 3;
+/// Height of a glyph in pixels
+const GLYPH_HEIGHT: u32 = 5;
+/// Horizontal distance from the start of one character to the next
+const ADVANCE_X: i32 = 4;
+/// Vertical distance from the start of one line to the next
+const ADVANCE_Y: i32 = 6;
+
+/// Glyph drawn for characters that are not in the font
+const UNKNOWN: u16 = 0b111_111_111_111_111;
+
+/// Glyphs for ` ` to `` ` `` (ASCII 32 to 96), then `{` to `~` (ASCII 123 to 126).
+/// Each glyph is 5 rows of 3 bits, with the top row in the highest bits
+/// and the leftmost pixel of a row in its highest bit.
+#[rustfmt::skip]
+const GLYPHS: [u16; 69] = [
+    0b000_000_000_000_000, // ' '
+    0b010_010_010_000_010, // !
+    0b101_101_000_000_000, // "
+    0b101_111_101_111_101, // #
+    0b011_110_010_011_110, // $
+    0b101_001_010_100_101, // %
+    0b010_101_010_101_011, // &
+    0b010_010_000_000_000, // '
+    0b001_010_010_010_001, // (
+    0b100_010_010_010_100, // )
+    0b000_101_010_101_000, // *
+    0b000_010_111_010_000, // +
+    0b000_000_000_010_100, // ,
+    0b000_000_111_000_000, // -
+    0b000_000_000_000_010, // .
+    0b001_001_010_100_100, // /
+    0b111_101_101_101_111, // 0
+    0b010_110_010_010_111, // 1
+    0b111_001_111_100_111, // 2
+    0b111_001_111_001_111, // 3
+    0b101_101_111_001_001, // 4
+    0b111_100_111_001_111, // 5
+    0b111_100_111_101_111, // 6
+    0b111_001_001_001_001, // 7
+    0b111_101_111_101_111, // 8
+    0b111_101_111_001_111, // 9
+    0b000_010_000_010_000, // :
+    0b000_010_000_010_100, // ;
+    0b001_010_100_010_001, // <
+    0b000_111_000_111_000, // =
+    0b100_010_001_010_100, // >
+    0b111_001_010_000_010, // ?
+    0b010_101_111_100_011, // @
+    0b010_101_111_101_101, // A
+    0b110_101_110_101_110, // B
+    0b011_100_100_100_011, // C
+    0b110_101_101_101_110, // D
+    0b111_100_110_100_111, // E
+    0b111_100_110_100_100, // F
+    0b011_100_101_101_011, // G
+    0b101_101_111_101_101, // H
+    0b111_010_010_010_111, // I
+    0b001_001_001_101_010, // J
+    0b101_101_110_101_101, // K
+    0b100_100_100_100_111, // L
+    0b101_111_111_101_101, // M
+    0b110_101_101_101_101, // N
+    0b010_101_101_101_010, // O
+    0b110_101_110_100_100, // P
+    0b010_101_101_110_011, // Q
+    0b110_101_110_101_101, // R
+    0b011_100_010_001_110, // S
+    0b111_010_010_010_010, // T
+    0b101_101_101_101_111, // U
+    0b101_101_101_101_010, // V
+    0b101_101_111_111_101, // W
+    0b101_101_010_101_101, // X
+    0b101_101_010_010_010, // Y
+    0b111_001_010_100_111, // Z
+    0b110_100_100_100_110, // [
+    0b100_100_010_001_001, // \
+    0b011_001_001_001_011, // ]
+    0b010_101_000_000_000, // ^
+    0b000_000_000_000_111, // _
+    0b100_010_000_000_000, // `
+    0b011_010_110_010_011, // {
+    0b010_010_010_010_010, // |
+    0b110_010_011_010_110, // }
+    0b000_011_110_000_000, // ~
+];
+
+/// Returns the glyph for an ASCII character. Lowercase letters use the uppercase glyphs
+fn glyph(c: u8) -> u16 {
+    match c {
+        b'a'..=b'z' => GLYPHS[(c - b'a' + b'A' - b' ') as usize],
+        b' '..=b'`' => GLYPHS[(c - b' ') as usize],
+        b'{'..=b'~' => GLYPHS[(c - b'{') as usize + 65],
+        _ => UNKNOWN,
+    }
+}
+
+/// Returns the positions of the pixels of text drawn with its top left at x, y.
+/// `\n` starts a new line below the first character
+pub fn text_pixels(text: &[u8], x: i32, y: i32) -> Vec<(i32, i32)> {
+    let mut pixels = Vec::new();
+    let (mut char_x, mut char_y) = (x, y);
+    for &c in text {
+        if c == b'\n' {
+            char_x = x;
+            char_y = char_y.saturating_add(ADVANCE_Y);
+            continue;
+        }
+        let glyph = glyph(c);
+        for row in 0..GLYPH_HEIGHT {
+            for column in 0..GLYPH_WIDTH {
+                let bit = (GLYPH_HEIGHT - row) * GLYPH_WIDTH - 1 - column;
+                if (glyph >> bit) & 1 != 0 {
+                    pixels.push((
+                        char_x.saturating_add(column as i32),
+                        char_y.saturating_add(row as i32),
+                    ));
+                }
+            }
+        }
+        char_x = char_x.saturating_add(ADVANCE_X);
+    }
+    pixels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn space_is_empty() {
+        assert!(text_pixels(b"   ", 0, 0).is_empty());
+    }
+
+    #[test]
+    fn unknown_characters_are_a_box() {
+        let mut pixels = text_pixels(b"\x01", 10, 20);
+        pixels.sort();
+        let mut expected = Vec::new();
+        for x in 10..13 {
+            for y in 20..25 {
+                expected.push((x, y));
+            }
+        }
+        assert_eq!(pixels, expected);
+    }
+
+    #[test]
+    fn lowercase_is_drawn_as_uppercase() {
+        assert_eq!(
+            text_pixels(b"hello, world", 0, 0),
+            text_pixels(b"HELLO, WORLD", 0, 0)
+        );
+    }
+
+    #[test]
+    fn characters_and_lines_advance() {
+        let column = |x, y| (0..5).map(move |row| (x, y + row));
+        let expected: Vec<_> = column(11, 20)
+            .chain(column(15, 20))
+            .chain(column(11, 26))
+            .collect();
+        assert_eq!(text_pixels(b"||\n|", 10, 20), expected);
+    }
+
+    #[test]
+    fn text_at_the_edge_does_not_overflow() {
+        let pixels = text_pixels(b"\x01\x01\n\x01", i32::MAX - 1, i32::MAX - 1);
+        assert!(pixels
+            .iter()
+            .all(|&(x, y)| x >= i32::MAX - 1 && y >= i32::MAX - 1));
+    }
+}
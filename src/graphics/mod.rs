@@ -2,48 +2,187 @@
 
 use sdl2::event::Event;
 use sdl2::keyboard::Scancode;
-use sdl2::pixels::{Color, PixelFormat, PixelFormatEnum};
-use sdl2::rect::Rect;
+use sdl2::pixels::{Color, PixelFormatEnum};
+use sdl2::rect::{Point, Rect};
 use sdl2::render::{BlendMode, Canvas, Texture, TextureCreator, TextureQuery};
 use sdl2::video::{Window, WindowContext};
 use sdl2::{EventPump, TimerSubsystem};
 
 use crate::error::{Error, IResult};
 use crate::util::append_u32;
+use structopt::clap::arg_enum;
 
-//in pixels (defined by PIX_SIZE)
-const SCREEN_WIDTH: u32 = 96;
-const SCREEN_HEIGHT: u32 = 64;
+mod font;
+mod shapes;
 
-const PIX_SIZE: u32 = 8;
+use shapes::Bounds;
 
 const PIXEL_FORMAT: PixelFormatEnum = PixelFormatEnum::RGBA8888;
 
+/// Number of colors in the palette used by palette mode and indexed framebuffers
+pub const PALETTE_COLORS: usize = 256;
+
+arg_enum! {
+    /// How the pixels of a framebuffer are stored in memory
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum FramebufferFormat {
+        Rgba,
+        Indexed,
+    }
+}
+
+impl FramebufferFormat {
+    /// Sizes in bytes of the palette and of the pixels of a w * h framebuffer
+    pub fn memory_sizes(self, w: u32, h: u32) -> (usize, usize) {
+        let pixels = w as usize * h as usize;
+        match self {
+            // one RGBA8888 u32 per pixel
+            FramebufferFormat::Rgba => (0, pixels * 4),
+            // RGBA8888 u32 colors, then one palette index byte per pixel
+            FramebufferFormat::Indexed => (PALETTE_COLORS * 4, pixels),
+        }
+    }
+}
+
+/// Size and title of the window
+pub struct GraphicsConfig {
+    /// in pixels (defined by scale)
+    pub width: u32,
+    pub height: u32,
+    /// width and height of each pixel on the real screen
+    pub scale: u32,
+    pub title: String,
+    /// Memory mapped framebuffer drawn on present
+    pub framebuffer: Option<FramebufferFormat>,
+    /// Colors are indices into a palette in memory
+    pub palette_mode: bool,
+}
+
 /// handles drawing to the screen and imput events
 pub struct Graphics {
     canvas: Canvas<Window>,
     event_pump: EventPump,
     timer: TimerSubsystem,
+    width: u32,
+    height: u32,
+    scale: u32,
+    /// Index of the target that is drawn to (see Targets)
+    target: u32,
+    target_width: u32,
+    target_height: u32,
+    /// Drawing only changes pixels inside this, which is always inside the target
+    clip: Bounds,
+    /// The screen holds palette indices (see index_color) instead of colors
+    palette_mode: bool,
 }
 
 /// Creates sprites, which are stored in the Sprites struct
-pub struct SpriteCreator(TextureCreator<WindowContext>);
+pub struct SpriteCreator {
+    texture_creator: TextureCreator<WindowContext>,
+    /// Sprite colors are palette indices
+    palette_mode: bool,
+}
 
 /// Holds sprites, which can be used by Graphics.
 /// This is separate from Graphics and SpriteCreator due to lifetime issues.
 pub struct Sprites<'a> {
     sprite_creator: &'a SpriteCreator,
-    textures: Vec<Texture<'a>>,
+    /// Indexed by sprite index. Freed sprites are None
+    sprites: Vec<Option<Sprite<'a>>>,
+}
+
+struct Sprite<'a> {
+    texture: Texture<'a>,
+    /// The format of the data the sprite was created from, so it can be updated
+    format: SpriteFormat,
+}
+
+/// Textures that can be drawn to, which can be used by Graphics.
+/// Index 0 is the screen, which is shown in the window on present.
+/// This is separate from Graphics for the same reason as Sprites.
+pub struct Targets<'a> {
+    sprite_creator: &'a SpriteCreator,
+    targets: Vec<Target<'a>>,
+}
+
+struct Target<'a> {
+    texture: Texture<'a>,
+    /// Set if the target is drawn over the screen on present
+    layer: Option<Layer>,
+}
+
+/// Where a target is drawn over the screen on present
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Layer {
+    /// Layers are drawn from the lowest order to the highest
+    order: u32,
+    x: i32,
+    y: i32,
+}
+
+/// How the pixels of a sprite are stored in memory
+enum SpriteFormat {
+    /// One bit per pixel, set bits have the color
+    Mono(u32),
+    /// One RGBA8888 u32 per pixel
+    Rgba,
+    /// One u32 palette index per pixel, used instead of Rgba in palette mode
+    Indices,
+    /// palette colors, bits per pixel
+    Palette(Vec<u32>, u32),
+}
+
+/// The transforms sprite_ex draws a sprite with, decoded from its flags
+#[derive(Debug, PartialEq)]
+struct SpriteTransform {
+    flip_horizontal: bool,
+    flip_vertical: bool,
+    /// Clockwise, after flipping
+    quarter_turns: u32,
+    /// Always at least 1
+    scale: u32,
+}
+
+/// A screen sized texture that is copied from memory on present
+pub struct Framebuffer<'a> {
+    texture: Texture<'a>,
+    format: FramebufferFormat,
+    width: u32,
+    /// Byte index of the palette in memory
+    palette_start: usize,
+    /// Byte index of the pixels in memory
+    pixels_start: usize,
+    /// RGBA8888 pixels converted from an indexed framebuffer
+    pixels: Vec<u8>,
+}
+
+/// Converts the palette indices on the screen and layers to colors on present, in palette mode
+pub struct PaletteScreen<'a> {
+    sprite_creator: &'a SpriteCreator,
+    /// The screen and layers are drawn to this, and read back as palette indices.
+    /// Both textures are screen sized, and are recreated when the screen size changes
+    composite: Texture<'a>,
+    /// The colors of the indices, which are drawn to the window
+    texture: Texture<'a>,
+    /// Byte index of the palette in memory
+    palette_start: usize,
+    /// RGBA8888 pixels converted from the indices
+    pixels: Vec<u8>,
 }
 
 impl Graphics {
     /// Attempts to make a new graphics object.
     /// Calling this will create the window and display it
-    pub fn try_new() -> IResult<Self> {
+    pub fn try_new(config: &GraphicsConfig) -> IResult<Self> {
+        check_screen_size(config.width, config.height, config.scale)?;
         let sdl = sdl2::init().convert_error()?;
         let video = sdl.video().convert_error()?;
         let window = video
-            .window("Title", SCREEN_WIDTH * PIX_SIZE, SCREEN_HEIGHT * PIX_SIZE)
+            .window(
+                &config.title,
+                config.width * config.scale,
+                config.height * config.scale,
+            )
             .position_centered()
             .build()
             .map_err(|e| Error::Graphics(e.to_string()))?;
@@ -51,9 +190,6 @@ impl Graphics {
             .into_canvas()
             .build()
             .map_err(|e| Error::Graphics(e.to_string()))?;
-        canvas
-            .set_scale(PIX_SIZE as f32, PIX_SIZE as f32)
-            .convert_error()?;
         let event_pump = sdl.event_pump().convert_error()?;
         let timer = sdl.timer().convert_error()?;
 
@@ -65,26 +201,197 @@ impl Graphics {
             canvas,
             event_pump,
             timer,
+            width: config.width,
+            height: config.height,
+            scale: config.scale,
+            target: 0,
+            target_width: config.width,
+            target_height: config.height,
+            clip: Bounds::from_rect(0, 0, config.width as i32, config.height as i32),
+            palette_mode: config.palette_mode,
         })
     }
 
-    /// Write backbuffer to screen
-    pub fn present(&mut self) {
+    /// Resizes the window to width * height pixels, each scale real pixels wide.
+    /// The screen is cleared
+    pub fn set_screen_size(
+        &mut self,
+        targets: &mut Targets,
+        width: u32,
+        height: u32,
+        scale: u32,
+    ) -> IResult<()> {
+        check_screen_size(width, height, scale)?;
+        self.canvas
+            .window_mut()
+            .set_size(width * scale, height * scale)
+            .map_err(|e| Error::Graphics(e.to_string()))?;
+        targets.targets[0].texture = targets.sprite_creator.create_target(width, height)?;
+        self.width = width;
+        self.height = height;
+        self.scale = scale;
+        let target = self.target;
+        self.set_target(targets, 0)?;
+        self.canvas.clear();
+        self.set_target(targets, target)
+    }
+
+    /// Makes drawing go to a target, where 0 is the screen. The clip rect is reset
+    pub fn set_target(&mut self, targets: &Targets, index: u32) -> IResult<()> {
+        let texture = targets.texture(index)?;
+        self.bind(Some(texture))?;
+        let TextureQuery { width, height, .. } = texture.query();
+        self.target = index;
+        self.target_width = width;
+        self.target_height = height;
+        self.set_clip_rect(None);
+        Ok(())
+    }
+
+    /// Creates a w * h target that starts out transparent. Returns its index
+    pub fn create_target(&mut self, targets: &mut Targets, w: u32, h: u32) -> IResult<u32> {
+        let index = targets.add(w, h)?;
+        let target = self.target;
+        self.set_target(targets, index)?;
+        self.clear_with(Color::RGBA(0, 0, 0, 0));
+        self.set_target(targets, target)?;
+        Ok(index)
+    }
+
+    /// Makes a target a layer, which is drawn over the screen with its top left at x, y on present.
+    /// Layers are drawn from the lowest order to the highest. An order of 0 removes the layer
+    pub fn set_layer(
+        &mut self,
+        targets: &mut Targets,
+        index: u32,
+        order: u32,
+        x: i32,
+        y: i32,
+    ) -> IResult<()> {
+        targets.texture(index)?;
+        if index == 0 {
+            return Err(Error::Graphics(String::from(
+                "the screen can not be a layer",
+            )));
+        }
+        targets.targets[index as usize].layer = if order == 0 {
+            None
+        } else {
+            Some(Layer { order, x, y })
+        };
+        Ok(())
+    }
+
+    /// Only allow drawing inside the rectangle (x, y, w, h), or the whole target if rect is None
+    pub fn set_clip_rect(&mut self, rect: Option<(i32, i32, i32, i32)>) {
+        let target = Bounds::from_rect(0, 0, self.target_width as i32, self.target_height as i32);
+        self.clip = match rect {
+            Some((x, y, w, h)) => Bounds::from_rect(x, y, w, h).intersect(target),
+            None => target,
+        };
+        self.apply_clip();
+    }
+
+    /// Passes the clip rect to SDL
+    fn apply_clip(&mut self) {
+        // an empty clip rect is never passed to SDL, nothing is drawn instead
+        if !self.clip.is_empty() {
+            self.canvas.set_clip_rect(Some(Rect::new(
+                self.clip.left,
+                self.clip.top,
+                self.clip.width(),
+                self.clip.height(),
+            )));
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Shows the screen in the window, with the layers drawn over it in order, then the framebuffer.
+    /// In palette mode, the screen and layers are converted to colors with the palette in memory
+    pub fn present(
+        &mut self,
+        targets: &Targets,
+        palette: Option<(&mut PaletteScreen, &[u8])>,
+        framebuffer: Option<&Framebuffer>,
+    ) -> IResult<()> {
+        match palette {
+            Some((palette_screen, memory)) => {
+                palette_screen.fit_screen(self.width, self.height)?;
+                self.bind(Some(&palette_screen.composite))?;
+                // shows as palette index 0
+                self.clear_with(Color::RGBA(0, 0, 0, 0));
+                self.composite(targets, 1)?;
+                let indices = self
+                    .canvas
+                    .read_pixels(Rect::new(0, 0, self.width, self.height), PIXEL_FORMAT)
+                    .convert_error()?;
+                let start = palette_screen.palette_start;
+                let palette = &memory[start..start + PALETTE_COLORS * 4];
+                palette_screen.pixels.clear();
+                for index in indices.chunks_exact(4) {
+                    // the index is in the red channel, which is the last byte of a RGBA8888 u32
+                    let color = index[3] as usize * 4;
+                    palette_screen
+                        .pixels
+                        .extend_from_slice(&palette[color..color + 4]);
+                }
+                upload_pixels(
+                    &mut palette_screen.texture,
+                    &palette_screen.pixels,
+                    self.width,
+                )?;
+                self.bind(None)?;
+                self.canvas
+                    .copy(&palette_screen.texture, None, None)
+                    .convert_error()?;
+            }
+            None => {
+                self.bind(None)?;
+                self.clear_with(Color::RGB(0, 0, 0));
+                self.composite(targets, self.scale)?;
+            }
+        }
+        if let Some(framebuffer) = framebuffer {
+            self.canvas
+                .copy(&framebuffer.texture, None, None)
+                .convert_error()?;
+        }
         self.canvas.present();
+
+        // SDL resets the clip rect of a target when drawing to it again
+        self.bind(Some(targets.texture(self.target)?))?;
+        self.apply_clip();
+        Ok(())
     }
 
-    /// Color is in RGBA8888 format
+    /// Color is in RGBA8888 format, or a palette index in palette mode
     pub fn draw_color(&mut self, color: u32) {
+        let color = if self.palette_mode {
+            index_color(color)
+        } else {
+            color
+        };
         self.canvas.set_draw_color(to_color(color));
     }
 
-    pub fn pixel(&mut self, x: u32, y: u32) -> IResult<()> {
-        self.canvas.draw_point((x as i32, y as i32)).convert_error()
+    pub fn pixel(&mut self, x: i32, y: i32) -> IResult<()> {
+        self.points(&[(x, y)])
     }
 
-    pub fn fill_rect(&mut self, x: u32, y: u32, w: u32, h: u32) -> IResult<()> {
+    pub fn fill_rect(&mut self, x: i32, y: i32, w: i32, h: i32) -> IResult<()> {
+        let rect = Bounds::from_rect(x, y, w, h).intersect(self.clip);
+        if rect.is_empty() {
+            return Ok(());
+        }
         self.canvas
-            .fill_rect(Rect::new(x as i32, y as i32, w, h))
+            .fill_rect(Rect::new(rect.left, rect.top, rect.width(), rect.height()))
             .convert_error()
     }
 
@@ -114,61 +421,249 @@ impl Graphics {
             .is_scancode_pressed(scancode))
     }
 
-    pub fn sprite(&mut self, sprites: &Sprites, sprite_index: u32, x: u32, y: u32) -> IResult<()> {
-        if sprite_index as usize >= sprites.textures.len() {
-            return Err(Error::InvalidSpriteIndex(sprite_index));
+    pub fn sprite(&mut self, sprites: &Sprites, sprite_index: u32, x: i32, y: i32) -> IResult<()> {
+        self.copy(sprites.texture(sprite_index)?, x, y)
+    }
+
+    /// Draws a target with its top left at x, y
+    pub fn draw_target(&mut self, targets: &Targets, index: u32, x: i32, y: i32) -> IResult<()> {
+        let texture = targets.texture(index)?;
+        if index == self.target {
+            return Err(Error::Graphics(format!(
+                "target {} can not be drawn onto itself",
+                index
+            )));
         }
-        
-        let tex = &sprites.textures[sprite_index as usize];
+        self.copy(texture, x, y)
+    }
+
+    /// Draws part of a sprite (the whole sprite if source is None) with the transforms in flags.
+    /// Flags bit 0 flips horizontally, bit 1 flips vertically, bits 2-3 are the number of
+    /// clockwise quarter turns and bits 8-15 are the scale (0 is the same as 1).
+    /// Flips happen before rotation. x and y are the top left of the transformed sprite
+    pub fn sprite_ex(
+        &mut self,
+        sprites: &Sprites,
+        sprite_index: u32,
+        x: i32,
+        y: i32,
+        flags: u32,
+        source: Option<(u32, u32, u32, u32)>,
+    ) -> IResult<()> {
+        let tex = sprites.texture(sprite_index)?;
         let TextureQuery { width, height, .. } = tex.query();
+        let (src_x, src_y, src_w, src_h) = source.unwrap_or((0, 0, width, height));
+        let fits = |start: u32, len: u32, size: u32| match start.checked_add(len) {
+            Some(end) => len > 0 && end <= size,
+            None => false,
+        };
+        if !fits(src_x, src_w, width) || !fits(src_y, src_h, height) {
+            return Err(Error::Graphics(format!(
+                "source rectangle {}x{} at {}, {} is not inside sprite {}, which is {}x{}",
+                src_w, src_h, src_x, src_y, sprite_index, width, height
+            )));
+        }
+
+        let transform = SpriteTransform::from_flags(flags);
+        let (destination, drawn_w, drawn_h) = transform.destination(x, y, src_w, src_h);
+        if !self.is_visible(x, y, drawn_w, drawn_h) {
+            return Ok(());
+        }
         self.canvas
-            .copy(tex, None, Rect::new(x as i32, y as i32, width, height))
+            .copy_ex(
+                tex,
+                Rect::new(src_x as i32, src_y as i32, src_w, src_h),
+                destination,
+                f64::from(transform.quarter_turns * 90),
+                None,
+                transform.flip_horizontal,
+                transform.flip_vertical,
+            )
             .convert_error()
     }
 
     pub fn get_sprite_creator(&self) -> SpriteCreator {
-        SpriteCreator(self.canvas.texture_creator())
+        SpriteCreator {
+            texture_creator: self.canvas.texture_creator(),
+            palette_mode: self.palette_mode,
+        }
+    }
+
+    /// Draws ASCII text with the built in font, with its top left at x and y
+    pub fn text(&mut self, text: &[u8], x: i32, y: i32) -> IResult<()> {
+        self.points(&font::text_pixels(text, x, y))
     }
 
     /// Uses Bresenham's line algorithm
-    pub fn line(&mut self, x0: u32, y0: u32, x1: u32, y1: u32) -> IResult<()> {
-        let mut x0 = x0 as i32;
-        let mut y0 = y0 as i32;
-        let x1 = x1 as i32;
-        let y1 = y1 as i32;
-
-        let dx = (x1 - x0).abs();
-        let step_x = if x0 < x1 { 1 } else { -1 };
-        let dy = -(y1 - y0).abs();
-        let step_y = if y0 < y1 { 1 } else { -1 };
-        let mut error = dx + dy;
-        loop {
-            self.pixel(x0 as u32, y0 as u32)?;
-            if x0 == x1 && y0 == y1 {
-                break;
-            }
-            let error_2 = 2 * error;
-            if error_2 >= dy {
-                error += dy;
-                x0 += step_x
-            }
-            if error_2 <= dx {
-                error += dx;
-                y0 += step_y;
-            }
+    pub fn line(&mut self, x0: i32, y0: i32, x1: i32, y1: i32) -> IResult<()> {
+        self.points(&shapes::line(x0, y0, x1, y1, self.clip))
+    }
+
+    /// Draws the outline of a rectangle
+    pub fn rect(&mut self, x: i32, y: i32, w: i32, h: i32) -> IResult<()> {
+        self.spans(&shapes::rect(x, y, w, h, self.clip))
+    }
+
+    /// Draws the outline of an ellipse with its centre at cx, cy.
+    /// Nothing is drawn if a radius is negative
+    pub fn ellipse(&mut self, cx: i32, cy: i32, rx: i32, ry: i32) -> IResult<()> {
+        if rx < 0 || ry < 0 {
+            return Ok(());
+        }
+        self.spans(&shapes::ellipse(cx, cy, rx, ry, self.clip))
+    }
+
+    /// Draws a filled ellipse with its centre at cx, cy.
+    /// Nothing is drawn if a radius is negative
+    pub fn fill_ellipse(&mut self, cx: i32, cy: i32, rx: i32, ry: i32) -> IResult<()> {
+        if rx < 0 || ry < 0 {
+            return Ok(());
+        }
+        self.spans(&shapes::fill_ellipse(cx, cy, rx, ry, self.clip))
+    }
+
+    pub fn triangle(&mut self, points: [(i32, i32); 3]) -> IResult<()> {
+        self.points(&shapes::triangle(points, self.clip))
+    }
+
+    pub fn fill_triangle(&mut self, points: [(i32, i32); 3]) -> IResult<()> {
+        self.spans(&shapes::fill_triangle(points, self.clip))
+    }
+
+    /// Fills the area around x, y that is the same color as the pixel at x, y on the target
+    pub fn flood_fill(&mut self, x: i32, y: i32) -> IResult<()> {
+        if !self.clip.contains((x, y)) {
+            return Ok(());
+        }
+        let colors = self.read_target()?;
+        self.points(&shapes::flood_fill(
+            &colors,
+            self.target_width,
+            x,
+            y,
+            self.clip,
+        ))
+    }
+
+    /// Returns the RGBA8888 color of every pixel of the target, one row after another
+    fn read_target(&mut self) -> IResult<Vec<u32>> {
+        let pixels = self
+            .canvas
+            .read_pixels(
+                Rect::new(0, 0, self.target_width, self.target_height),
+                PIXEL_FORMAT,
+            )
+            .convert_error()?;
+        Ok(pixels
+            .chunks_exact(4)
+            .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+            .collect())
+    }
+
+    /// Makes drawing go to a texture, or to the window if texture is None
+    fn bind(&mut self, texture: Option<&Texture>) -> IResult<()> {
+        let texture = texture.map_or(std::ptr::null_mut(), |texture| texture.raw());
+        // sdl2 only allows drawing to a texture inside a closure, which does not fit
+        // drawing to a target for many intrinsic calls
+        if unsafe { sdl2::sys::SDL_SetRenderTarget(self.canvas.raw(), texture) } == 0 {
+            Ok(())
+        } else {
+            Err(Error::Graphics(sdl2::get_error()))
+        }
+    }
+
+    /// Clears the target with color, without changing the draw color
+    pub fn clear_with(&mut self, color: Color) {
+        let draw_color = self.canvas.draw_color();
+        self.canvas.set_draw_color(color);
+        self.canvas.clear();
+        self.canvas.set_draw_color(draw_color);
+    }
+
+    /// Draws the screen and then the layers to the whole of the target, which is scale times larger
+    fn composite(&mut self, targets: &Targets, scale: u32) -> IResult<()> {
+        let screen = targets.texture(0)?;
+        let (w, h) = (self.width * scale, self.height * scale);
+        self.canvas
+            .copy(screen, None, Rect::new(0, 0, w, h))
+            .convert_error()?;
+        for (texture, layer) in targets.layers() {
+            let TextureQuery { width, height, .. } = texture.query();
+            let position = |n: i32| n.saturating_mul(scale as i32);
+            let destination = Rect::new(
+                position(layer.x),
+                position(layer.y),
+                width * scale,
+                height * scale,
+            );
+            self.canvas
+                .copy(texture, None, destination)
+                .convert_error()?;
         }
         Ok(())
     }
+
+    /// Draws a texture with its top left at x, y
+    fn copy(&mut self, texture: &Texture, x: i32, y: i32) -> IResult<()> {
+        let TextureQuery { width, height, .. } = texture.query();
+        if !self.is_visible(x, y, width, height) {
+            return Ok(());
+        }
+        self.canvas
+            .copy(texture, None, Rect::new(x, y, width, height))
+            .convert_error()
+    }
+
+    /// Returns true if any of the w * h rectangle at x, y is inside the clip rect
+    fn is_visible(&self, x: i32, y: i32, w: u32, h: u32) -> bool {
+        let w = w.min(i32::MAX as u32) as i32;
+        let h = h.min(i32::MAX as u32) as i32;
+        !Bounds::from_rect(x, y, w, h)
+            .intersect(self.clip)
+            .is_empty()
+    }
+
+    /// Draws the points that are inside the clip rect
+    fn points(&mut self, points: &[(i32, i32)]) -> IResult<()> {
+        let points: Vec<Point> = points
+            .iter()
+            .filter(|&&point| self.clip.contains(point))
+            .map(|&point| Point::from(point))
+            .collect();
+        self.canvas.draw_points(&points[..]).convert_error()
+    }
+
+    fn spans(&mut self, spans: &[shapes::Span]) -> IResult<()> {
+        let rects: Vec<Rect> = spans
+            .iter()
+            .map(|&(y, start, end)| Rect::new(start, y, (end - start + 1) as u32, 1))
+            .collect();
+        self.canvas.fill_rects(&rects).convert_error()
+    }
 }
 
 impl<'a> Sprites<'a> {
     pub fn new(sprite_creator: &'a SpriteCreator) -> Self {
         Sprites {
             sprite_creator,
-            textures: Vec::new(),
+            sprites: Vec::new(),
+        }
+    }
+
+    /// Returns a sprite, or an error if there is no sprite at the index
+    fn get(&self, sprite_index: u32) -> IResult<&Sprite<'a>> {
+        match self.sprites.get(sprite_index as usize) {
+            Some(Some(sprite)) => Ok(sprite),
+            Some(None) => Err(Error::SpriteFreed(sprite_index)),
+            None => Err(Error::InvalidSpriteIndex(sprite_index)),
         }
     }
 
+    /// Returns the texture of a sprite, or an error if there is no sprite at the index
+    fn texture(&self, sprite_index: u32) -> IResult<&Texture<'a>> {
+        self.get(sprite_index).map(|sprite| &sprite.texture)
+    }
+
     /// Create a monochromatic sprite, where each bit in data represents a pixel.
     /// Returns the index of the sprite
     /// (which can be used to identify the sprite when using it in Graphics).
@@ -180,37 +675,414 @@ impl<'a> Sprites<'a> {
                 w
             )));
         }
+        let color = self.sprite_creator.color(color);
+        self.add_sprite(SpriteFormat::Mono(color), data, w, h)
+    }
+
+    /// Create a full color sprite, where each 4 bytes of data is a RGBA8888 pixel
+    /// (or a palette index in palette mode, stored the same way as a u32 in memory).
+    /// Returns the index of the sprite
+    pub fn create_sprite(&mut self, data: &[u8], w: u32, h: u32) -> IResult<u32> {
+        let format = if self.sprite_creator.palette_mode {
+            SpriteFormat::Indices
+        } else {
+            SpriteFormat::Rgba
+        };
+        self.add_sprite(format, data, w, h)
+    }
+
+    /// Create a sprite where each pixel is an index into palette, which holds RGBA8888 colors.
+    /// Pixels are bits_per_pixel (1, 2, 4 or 8) bits wide, packed starting from the most significant bits.
+    /// Each row starts on a new byte, so w can be anything.
+    /// Returns the index of the sprite
+    pub fn create_sprite_palette(
+        &mut self,
+        data: &[u8],
+        w: u32,
+        h: u32,
+        palette: Vec<u32>,
+        bits_per_pixel: u32,
+    ) -> IResult<u32> {
+        palette_row_len(w, bits_per_pixel)?;
+        let palette = palette
+            .into_iter()
+            .map(|color| self.sprite_creator.color(color))
+            .collect();
+        self.add_sprite(SpriteFormat::Palette(palette, bits_per_pixel), data, w, h)
+    }
+
+    /// Number of bytes of data needed by update_sprite
+    pub fn data_len(&self, sprite_index: u32) -> IResult<usize> {
+        let sprite = self.get(sprite_index)?;
+        let TextureQuery { width, height, .. } = sprite.texture.query();
+        Ok(sprite.format.data_len(width, height))
+    }
+
+    /// Replaces the pixels of a sprite with data, which is in the same format
+    /// (and uses the same color or palette) as when the sprite was created
+    pub fn update_sprite(&mut self, sprite_index: u32, data: &[u8]) -> IResult<()> {
+        self.get(sprite_index)?;
+        if let Some(Some(sprite)) = self.sprites.get_mut(sprite_index as usize) {
+            let TextureQuery { width, height, .. } = sprite.texture.query();
+            let pixels = sprite.format.to_pixels(data, width, height);
+            upload_pixels(&mut sprite.texture, &pixels, width)?;
+        }
+        Ok(())
+    }
 
-        let mut tex = self
+    /// Destroys a sprite. Its index will be reused by the next created sprite
+    pub fn free_sprite(&mut self, sprite_index: u32) -> IResult<()> {
+        self.get(sprite_index)?;
+        self.sprites[sprite_index as usize] = None;
+        Ok(())
+    }
+
+    /// Create a texture from data in format and return its sprite index
+    fn add_sprite(&mut self, format: SpriteFormat, data: &[u8], w: u32, h: u32) -> IResult<u32> {
+        // every sprite constructor goes through here, so empty sprites are never converted
+        check_sprite_size(w, h)?;
+        let mut texture = self
             .sprite_creator
-            .0
+            .texture_creator
             .create_texture_static(PIXEL_FORMAT, w, h)
             .map_err(|e| Error::Graphics(e.to_string()))?;
-        tex.set_blend_mode(BlendMode::Blend);
-        let mut new_tex_data = Vec::new();
-        for byte in data {
-            for bit_index in (0..8).rev() {
-                if (byte >> bit_index) & 1u8 != 0u8 {
-                    append_u32(&mut new_tex_data, color);
-                } else {
-                    append_u32(&mut new_tex_data, 0);
+        texture.set_blend_mode(BlendMode::Blend);
+        upload_pixels(&mut texture, &format.to_pixels(data, w, h), w)?;
+
+        let index = insert_in_free_slot(&mut self.sprites, Sprite { texture, format });
+        Ok(index as u32)
+    }
+}
+
+impl<'a> Targets<'a> {
+    /// Creates the targets, with a w * h screen
+    pub fn try_new(sprite_creator: &'a SpriteCreator, w: u32, h: u32) -> IResult<Self> {
+        Ok(Targets {
+            sprite_creator,
+            targets: vec![Target {
+                texture: sprite_creator.create_target(w, h)?,
+                layer: None,
+            }],
+        })
+    }
+
+    /// Returns the texture of a target, or an error if there is no target at the index
+    fn texture(&self, index: u32) -> IResult<&Texture<'a>> {
+        self.targets
+            .get(index as usize)
+            .map(|target| &target.texture)
+            .ok_or(Error::InvalidTargetIndex(index))
+    }
+
+    /// Adds a w * h target and returns its index
+    fn add(&mut self, w: u32, h: u32) -> IResult<u32> {
+        if w == 0 || h == 0 {
+            return Err(Error::Graphics(format!("invalid target size {}x{}", w, h)));
+        }
+        self.targets.push(Target {
+            texture: self.sprite_creator.create_target(w, h)?,
+            layer: None,
+        });
+        Ok((self.targets.len() - 1) as u32)
+    }
+
+    /// The layers in the order they are drawn
+    fn layers(&self) -> Vec<(&Texture<'a>, Layer)> {
+        sort_layers(self.targets.iter().map(|target| target.layer))
+            .into_iter()
+            .map(|(index, layer)| (&self.targets[index].texture, layer))
+            .collect()
+    }
+}
+
+impl SpriteCreator {
+    /// Converts a color of a sprite to the color stored in its texture
+    fn color(&self, color: u32) -> u32 {
+        if self.palette_mode {
+            index_color(color)
+        } else {
+            color
+        }
+    }
+
+    /// Creates a texture that is updated often
+    fn create_streaming(&self, w: u32, h: u32, blend_mode: BlendMode) -> IResult<Texture<'_>> {
+        let mut texture = self
+            .texture_creator
+            .create_texture_streaming(PIXEL_FORMAT, w, h)
+            .map_err(|e| Error::Graphics(e.to_string()))?;
+        texture.set_blend_mode(blend_mode);
+        Ok(texture)
+    }
+
+    /// Creates a texture that can be drawn to
+    fn create_target(&self, w: u32, h: u32) -> IResult<Texture<'_>> {
+        let mut texture = self
+            .texture_creator
+            .create_texture_target(PIXEL_FORMAT, w, h)
+            .map_err(|e| Error::Graphics(e.to_string()))?;
+        texture.set_blend_mode(BlendMode::Blend);
+        Ok(texture)
+    }
+}
+
+impl<'a> Framebuffer<'a> {
+    /// Creates a w * h framebuffer, whose palette (if it is indexed) and pixels are in memory
+    pub fn try_new(
+        sprite_creator: &'a SpriteCreator,
+        format: FramebufferFormat,
+        w: u32,
+        h: u32,
+        palette_start: usize,
+        pixels_start: usize,
+    ) -> IResult<Self> {
+        Ok(Framebuffer {
+            texture: sprite_creator.create_streaming(w, h, BlendMode::Blend)?,
+            format,
+            width: w,
+            palette_start,
+            pixels_start,
+            pixels: Vec::new(),
+        })
+    }
+
+    /// Copies the palette and pixels from memory to the texture
+    pub fn update(&mut self, memory: &[u8]) -> IResult<()> {
+        let TextureQuery { width, height, .. } = self.texture.query();
+        let (_, pixels_size) = self.format.memory_sizes(width, height);
+        let pixels = &memory[self.pixels_start..self.pixels_start + pixels_size];
+        match self.format {
+            FramebufferFormat::Rgba => upload_pixels(&mut self.texture, pixels, self.width),
+            FramebufferFormat::Indexed => {
+                let palette = &memory[self.palette_start..self.palette_start + PALETTE_COLORS * 4];
+                self.pixels.clear();
+                for &index in pixels {
+                    let color = index as usize * 4;
+                    self.pixels.extend_from_slice(&palette[color..color + 4]);
                 }
+                upload_pixels(&mut self.texture, &self.pixels, self.width)
             }
         }
-        // the pitch (in bytes) is w * 4 because there are 4 bytes (RGBA8888) per pixel
-        tex.update(None, &new_tex_data, (w * 4) as usize)
-            .map_err(|e| Error::Graphics(e.to_string()))?;
-        self.textures.push(tex);
+    }
+}
+
+impl<'a> PaletteScreen<'a> {
+    /// Creates a PaletteScreen for a w * h screen, whose palette starts at palette_start in memory
+    pub fn try_new(
+        sprite_creator: &'a SpriteCreator,
+        w: u32,
+        h: u32,
+        palette_start: usize,
+    ) -> IResult<Self> {
+        Ok(PaletteScreen {
+            sprite_creator,
+            composite: sprite_creator.create_target(w, h)?,
+            // the window is replaced, not blended
+            texture: sprite_creator.create_streaming(w, h, BlendMode::None)?,
+            palette_start,
+            pixels: Vec::new(),
+        })
+    }
+
+    /// Recreates the textures if the screen size changed
+    fn fit_screen(&mut self, w: u32, h: u32) -> IResult<()> {
+        let TextureQuery { width, height, .. } = self.texture.query();
+        if (width, height) != (w, h) {
+            self.composite = self.sprite_creator.create_target(w, h)?;
+            self.texture = self
+                .sprite_creator
+                .create_streaming(w, h, BlendMode::None)?;
+        }
+        Ok(())
+    }
+}
+
+impl SpriteFormat {
+    /// Number of bytes of data in this format for a sprite of size w * h
+    fn data_len(&self, w: u32, h: u32) -> usize {
+        let row_len = match self {
+            SpriteFormat::Mono(_) => w as usize / 8,
+            SpriteFormat::Rgba | SpriteFormat::Indices => w as usize * 4,
+            SpriteFormat::Palette(_, bits_per_pixel) => {
+                (w as usize * *bits_per_pixel as usize).div_ceil(8)
+            }
+        };
+        row_len * h as usize
+    }
+
+    /// Converts data in this format to RGBA8888 pixels
+    fn to_pixels(&self, data: &[u8], w: u32, h: u32) -> Vec<u8> {
+        match self {
+            SpriteFormat::Mono(color) => {
+                let mut pixels = Vec::new();
+                for byte in data {
+                    for bit_index in (0..8).rev() {
+                        if (byte >> bit_index) & 1u8 != 0u8 {
+                            append_u32(&mut pixels, *color);
+                        } else {
+                            append_u32(&mut pixels, 0);
+                        }
+                    }
+                }
+                pixels
+            }
+            SpriteFormat::Rgba => data.to_vec(),
+            SpriteFormat::Indices => {
+                let mut pixels = Vec::new();
+                for index in data.chunks_exact(4) {
+                    let index = u32::from_le_bytes([index[0], index[1], index[2], index[3]]);
+                    append_u32(&mut pixels, index_color(index));
+                }
+                pixels
+            }
+            SpriteFormat::Palette(palette, bits_per_pixel) => {
+                let mask = (1u32 << bits_per_pixel) - 1;
+                let row_len = self.data_len(w, 1);
+                let mut pixels = Vec::new();
+                for row in data.chunks(row_len).take(h as usize) {
+                    for x in 0..w {
+                        let bit_offset = x * bits_per_pixel;
+                        let byte = u32::from(row[(bit_offset / 8) as usize]);
+                        let shift = 8 - bits_per_pixel - bit_offset % 8;
+                        let color = palette
+                            .get(((byte >> shift) & mask) as usize)
+                            .copied()
+                            .unwrap_or(0);
+                        append_u32(&mut pixels, color);
+                    }
+                }
+                pixels
+            }
+        }
+    }
+}
+
+impl SpriteTransform {
+    fn from_flags(flags: u32) -> Self {
+        let mut flip_horizontal = flags & 1 != 0;
+        let mut flip_vertical = flags & 2 != 0;
+        let mut quarter_turns = (flags >> 2) & 3;
+        // flipping both ways is a half turn (and sdl2 can not pass both flips to SDL)
+        if flip_horizontal && flip_vertical {
+            flip_horizontal = false;
+            flip_vertical = false;
+            quarter_turns = (quarter_turns + 2) % 4;
+        }
+        let scale = match (flags >> 8) & 0xFF {
+            0 => 1,
+            scale => scale,
+        };
+        SpriteTransform {
+            flip_horizontal,
+            flip_vertical,
+            quarter_turns,
+            scale,
+        }
+    }
 
-        Ok((self.textures.len() - 1) as u32)
+    /// Returns the rectangle to pass to SDL for a src_w * src_h source drawn with this transform,
+    /// and the width and height of the transformed sprite, whose top left is at x, y
+    fn destination(&self, x: i32, y: i32, src_w: u32, src_h: u32) -> (Rect, u32, u32) {
+        let (w, h) = (src_w * self.scale, src_h * self.scale);
+        // SDL rotates around the centre of the destination, so move it to keep the
+        // top left of the rotated sprite at x, y
+        if self.quarter_turns % 2 == 1 {
+            let offset = (h as i32 - w as i32) / 2;
+            (Rect::new(x + offset, y - offset, w, h), h, w)
+        } else {
+            (Rect::new(x, y, w, h), w, h)
+        }
+    }
+}
+
+/// Copies RGBA8888 pixels to the whole of a texture that is w pixels wide
+fn upload_pixels(texture: &mut Texture, pixels: &[u8], w: u32) -> IResult<()> {
+    // the pitch (in bytes) is w * 4 because there are 4 bytes (RGBA8888) per pixel
+    texture
+        .update(None, pixels, (w * 4) as usize)
+        .map_err(|e| Error::Graphics(e.to_string()))
+}
+
+/// Returns the target indices and layers of the targets that are layers, in the order they are
+/// drawn. Layers with the same order are drawn by index
+fn sort_layers(layers: impl Iterator<Item = Option<Layer>>) -> Vec<(usize, Layer)> {
+    let mut layers: Vec<_> = layers
+        .enumerate()
+        .filter_map(|(index, layer)| layer.map(|layer| (index, layer)))
+        .collect();
+    // sort_by_key is stable, so the index order is kept
+    layers.sort_by_key(|&(_, layer)| layer.order);
+    layers
+}
+
+/// Puts item in the first freed (None) slot, or at the end if no slot is free,
+/// and returns its index
+fn insert_in_free_slot<T>(slots: &mut Vec<Option<T>>, item: T) -> usize {
+    match slots.iter().position(Option::is_none) {
+        Some(index) => {
+            slots[index] = Some(item);
+            index
+        }
+        None => {
+            slots.push(Some(item));
+            slots.len() - 1
+        }
+    }
+}
+
+/// Returns the number of bytes in each row of a palette sprite,
+/// or an error if bits_per_pixel is not supported
+pub fn palette_row_len(w: u32, bits_per_pixel: u32) -> IResult<usize> {
+    match bits_per_pixel {
+        1 | 2 | 4 | 8 => Ok((w as usize * bits_per_pixel as usize).div_ceil(8)),
+        _ => Err(Error::Graphics(format!(
+            "palette sprites must have 1, 2, 4 or 8 bits per pixel. {} supplied",
+            bits_per_pixel
+        ))),
+    }
+}
+
+/// Checks that a sprite is not empty
+pub fn check_sprite_size(w: u32, h: u32) -> IResult<()> {
+    if w == 0 || h == 0 {
+        Err(Error::Graphics(format!("invalid sprite size {}x{}", w, h)))
+    } else {
+        Ok(())
     }
 }
 
-/// Formates the u32 based on PIXEL_FORMAT const
+/// Checks that a screen is not empty, and that its window is not too large
+fn check_screen_size(width: u32, height: u32, scale: u32) -> IResult<()> {
+    // in real pixels
+    const MAX_WINDOW_SIZE: u32 = 16384;
+    let fits = |pixels: u32| match pixels.checked_mul(scale) {
+        Some(size) => size > 0 && size <= MAX_WINDOW_SIZE,
+        None => false,
+    };
+    if fits(width) && fits(height) {
+        Ok(())
+    } else {
+        Err(Error::Graphics(format!(
+            "invalid screen size {}x{} at scale {}",
+            width, height, scale
+        )))
+    }
+}
+
+/// Converts a RGBA8888 u32 to a Color
 fn to_color(n: u32) -> Color {
-    let pixel_format =
-        unsafe { PixelFormat::from_ll(sdl2::sys::SDL_AllocFormat(PIXEL_FORMAT as u32)) };
-    Color::from_u32(&pixel_format, n)
+    let [r, g, b, a] = n.to_be_bytes();
+    Color::RGBA(r, g, b, a)
+}
+
+/// Returns the RGBA8888 color that stores a palette index on the screen in palette mode.
+/// The index is in the red channel, and indices past the end of the palette are transparent
+fn index_color(index: u32) -> u32 {
+    if (index as usize) < PALETTE_COLORS {
+        index << 24 | 0xFF
+    } else {
+        0
+    }
 }
 
 /// Trait for converting something to an Error::Graphics(_)
@@ -223,3 +1095,243 @@ impl<T> ToGraphicsError<T> for Result<T, String> {
         self.map_err(Error::Graphics)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads back the u32 colors of pixels made by SpriteFormat::to_pixels
+    fn colors(pixels: Vec<u8>) -> Vec<u32> {
+        pixels
+            .chunks_exact(4)
+            .map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+            .collect()
+    }
+
+    fn palette(bits_per_pixel: u32) -> SpriteFormat {
+        let palette = (0..1 << bits_per_pixel).map(|i| 100 + i).collect();
+        SpriteFormat::Palette(palette, bits_per_pixel)
+    }
+
+    #[test]
+    fn data_len_of_each_format() {
+        assert_eq!(SpriteFormat::Mono(1).data_len(16, 3), 6);
+        assert_eq!(SpriteFormat::Rgba.data_len(3, 5), 60);
+        assert_eq!(SpriteFormat::Indices.data_len(3, 5), 60);
+        assert_eq!(palette(1).data_len(5, 2), 2);
+        assert_eq!(palette(1).data_len(9, 2), 4);
+        assert_eq!(palette(2).data_len(5, 2), 4);
+        assert_eq!(palette(4).data_len(5, 2), 6);
+        assert_eq!(palette(8).data_len(5, 2), 10);
+    }
+
+    #[test]
+    fn palette_rows_start_on_a_new_byte() {
+        assert_eq!(palette_row_len(1, 1).unwrap(), 1);
+        assert_eq!(palette_row_len(9, 1).unwrap(), 2);
+        assert_eq!(palette_row_len(3, 2).unwrap(), 1);
+        assert_eq!(palette_row_len(5, 2).unwrap(), 2);
+        assert_eq!(palette_row_len(3, 4).unwrap(), 2);
+        assert_eq!(palette_row_len(3, 8).unwrap(), 3);
+        for bits_per_pixel in &[0, 3, 16, 32] {
+            assert!(palette_row_len(3, *bits_per_pixel).is_err());
+        }
+    }
+
+    #[test]
+    fn mono_pixels_start_at_the_most_significant_bit() {
+        let pixels = SpriteFormat::Mono(7).to_pixels(&[0b1010_0001, 0b0100_0000], 8, 2);
+        assert_eq!(
+            colors(pixels),
+            vec![7, 0, 7, 0, 0, 0, 0, 7, 0, 7, 0, 0, 0, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn rgba_pixels_are_copied() {
+        let data = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+        assert_eq!(SpriteFormat::Rgba.to_pixels(&data, 3, 1), data.to_vec());
+    }
+
+    #[test]
+    fn palette_pixels_with_odd_widths() {
+        let rows =
+            |bits_per_pixel, data: &[u8]| colors(palette(bits_per_pixel).to_pixels(data, 3, 2));
+        assert_eq!(
+            rows(1, &[0b1010_0000, 0b0101_1111]),
+            vec![101, 100, 101, 100, 101, 100]
+        );
+        assert_eq!(
+            rows(2, &[0b0001_1011, 0b1110_0100]),
+            vec![100, 101, 102, 103, 102, 101]
+        );
+        assert_eq!(
+            rows(4, &[0x12, 0x3F, 0x45, 0x6F]),
+            vec![101, 102, 103, 104, 105, 106]
+        );
+        assert_eq!(
+            rows(8, &[1, 2, 3, 4, 5, 255]),
+            vec![101, 102, 103, 104, 105, 355]
+        );
+    }
+
+    #[test]
+    fn palette_indices_past_the_palette_are_transparent() {
+        let format = SpriteFormat::Palette(vec![5, 6], 2);
+        assert_eq!(
+            colors(format.to_pixels(&[0b0001_1011], 4, 1)),
+            vec![5, 6, 0, 0]
+        );
+    }
+
+    fn transform(
+        flip_horizontal: bool,
+        flip_vertical: bool,
+        quarter_turns: u32,
+        scale: u32,
+    ) -> SpriteTransform {
+        SpriteTransform {
+            flip_horizontal,
+            flip_vertical,
+            quarter_turns,
+            scale,
+        }
+    }
+
+    #[test]
+    fn sprite_flags_are_decoded() {
+        assert_eq!(
+            SpriteTransform::from_flags(0),
+            transform(false, false, 0, 1)
+        );
+        assert_eq!(SpriteTransform::from_flags(1), transform(true, false, 0, 1));
+        assert_eq!(SpriteTransform::from_flags(2), transform(false, true, 0, 1));
+        assert_eq!(
+            SpriteTransform::from_flags(3 << 2),
+            transform(false, false, 3, 1)
+        );
+        assert_eq!(
+            SpriteTransform::from_flags(1 | 1 << 2 | 4 << 8),
+            transform(true, false, 1, 4)
+        );
+        // bits above the scale are ignored
+        assert_eq!(
+            SpriteTransform::from_flags(0xFFFF_0000),
+            transform(false, false, 0, 1)
+        );
+    }
+
+    #[test]
+    fn sprite_scale_zero_is_one() {
+        assert_eq!(SpriteTransform::from_flags(0).scale, 1);
+        assert_eq!(SpriteTransform::from_flags(1 << 8).scale, 1);
+        assert_eq!(SpriteTransform::from_flags(255 << 8).scale, 255);
+    }
+
+    #[test]
+    fn flipping_both_ways_is_a_half_turn() {
+        assert_eq!(
+            SpriteTransform::from_flags(3),
+            transform(false, false, 2, 1)
+        );
+        assert_eq!(
+            SpriteTransform::from_flags(3 | 1 << 2),
+            transform(false, false, 3, 1)
+        );
+        assert_eq!(
+            SpriteTransform::from_flags(3 | 2 << 2),
+            transform(false, false, 0, 1)
+        );
+        assert_eq!(
+            SpriteTransform::from_flags(3 | 3 << 2),
+            transform(false, false, 1, 1)
+        );
+    }
+
+    #[test]
+    fn rotated_sprites_keep_their_top_left() {
+        let destination =
+            |flags, x, y, w, h| SpriteTransform::from_flags(flags).destination(x, y, w, h);
+        assert_eq!(destination(0, 5, 6, 8, 4), (Rect::new(5, 6, 8, 4), 8, 4));
+        assert_eq!(
+            destination(2 << 2, 5, 6, 8, 4),
+            (Rect::new(5, 6, 8, 4), 8, 4)
+        );
+        // the 8x4 destination is centred on the 4x8 rotated sprite
+        assert_eq!(
+            destination(1 << 2, 5, 6, 8, 4),
+            (Rect::new(3, 8, 8, 4), 4, 8)
+        );
+        assert_eq!(
+            destination(3 << 2, 5, 6, 4, 8),
+            (Rect::new(7, 4, 4, 8), 8, 4)
+        );
+        assert_eq!(
+            destination(1 << 2 | 2 << 8, 0, 0, 3, 1),
+            (Rect::new(-2, 2, 6, 2), 2, 6)
+        );
+    }
+
+    #[test]
+    fn new_items_are_added_at_the_end() {
+        let mut slots = Vec::new();
+        assert_eq!(insert_in_free_slot(&mut slots, 'a'), 0);
+        assert_eq!(insert_in_free_slot(&mut slots, 'b'), 1);
+        assert_eq!(slots, vec![Some('a'), Some('b')]);
+    }
+
+    #[test]
+    fn freed_slots_are_reused_lowest_first() {
+        let mut slots = vec![Some('a'), None, Some('c'), None];
+        assert_eq!(insert_in_free_slot(&mut slots, 'x'), 1);
+        assert_eq!(insert_in_free_slot(&mut slots, 'y'), 3);
+        assert_eq!(insert_in_free_slot(&mut slots, 'z'), 4);
+        assert_eq!(
+            slots,
+            vec![Some('a'), Some('x'), Some('c'), Some('y'), Some('z')]
+        );
+    }
+
+    #[test]
+    fn framebuffer_memory_sizes() {
+        assert_eq!(
+            FramebufferFormat::Rgba.memory_sizes(96, 64),
+            (0, 96 * 64 * 4)
+        );
+        assert_eq!(
+            FramebufferFormat::Indexed.memory_sizes(96, 64),
+            (PALETTE_COLORS * 4, 96 * 64)
+        );
+        assert_eq!(FramebufferFormat::Rgba.memory_sizes(0, 64), (0, 0));
+        // does not overflow a u32
+        assert_eq!(
+            FramebufferFormat::Indexed.memory_sizes(65536, 65536),
+            (PALETTE_COLORS * 4, 1 << 32)
+        );
+    }
+
+    #[test]
+    fn palette_indices_are_stored_in_the_red_channel() {
+        assert_eq!(index_color(0), 0x0000_00FF);
+        assert_eq!(index_color(1), 0x0100_00FF);
+        assert_eq!(index_color(255), 0xFF00_00FF);
+        assert_eq!(index_color(256), 0);
+        assert_eq!(index_color(u32::MAX), 0);
+    }
+
+    #[test]
+    fn layers_are_sorted_by_order_then_index() {
+        let layer = |order| Some(Layer { order, x: 0, y: 0 });
+        let targets = vec![None, layer(3), layer(1), None, layer(3), layer(2), layer(1)];
+        let order: Vec<_> = sort_layers(targets.into_iter())
+            .into_iter()
+            .map(|(index, layer)| (index, layer.order))
+            .collect();
+        assert_eq!(order, vec![(2, 1), (6, 1), (5, 2), (1, 3), (4, 3)]);
+    }
+
+    #[test]
+    fn targets_without_layers_are_not_drawn() {
+        assert!(sort_layers(vec![None, None].into_iter()).is_empty());
+    }
+}
@@ -0,0 +1,447 @@
+//! Pixel coverage of shapes that SDL can not draw itself.
+//! Shapes are returned as points or as horizontal spans `(y, x_start, x_end)`,
+//! where both ends of a span are included.
+//! Only the pixels inside the given Bounds are returned, so shapes can be any size.
+
+pub type Span = (i32, i32, i32);
+
+/// A rectangle that shapes are clipped to. All four edges are included,
+/// so it is empty if left > right or top > bottom
+#[derive(Debug, Clone, Copy)]
+pub struct Bounds {
+    pub left: i32,
+    pub top: i32,
+    pub right: i32,
+    pub bottom: i32,
+}
+
+const EMPTY: Bounds = Bounds {
+    left: 0,
+    top: 0,
+    right: -1,
+    bottom: -1,
+};
+
+impl Bounds {
+    /// The bounds covering w * h pixels starting at x, y (which may be empty)
+    pub fn from_rect(x: i32, y: i32, w: i32, h: i32) -> Self {
+        if w <= 0 || h <= 0 {
+            return EMPTY;
+        }
+        let last = |start: i32, len: i32| {
+            (i64::from(start) + i64::from(len) - 1).min(i64::from(i32::MAX)) as i32
+        };
+        Bounds {
+            left: x,
+            top: y,
+            right: last(x, w),
+            bottom: last(y, h),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.left > self.right || self.top > self.bottom
+    }
+
+    pub fn width(&self) -> u32 {
+        (i64::from(self.right) - i64::from(self.left) + 1).max(0) as u32
+    }
+
+    pub fn height(&self) -> u32 {
+        (i64::from(self.bottom) - i64::from(self.top) + 1).max(0) as u32
+    }
+
+    pub fn intersect(&self, other: Bounds) -> Bounds {
+        Bounds {
+            left: self.left.max(other.left),
+            top: self.top.max(other.top),
+            right: self.right.min(other.right),
+            bottom: self.bottom.min(other.bottom),
+        }
+    }
+
+    pub fn contains(&self, (x, y): (i32, i32)) -> bool {
+        x >= self.left && x <= self.right && y >= self.top && y <= self.bottom
+    }
+
+    /// Returns the part of a span inside the bounds
+    fn clip_span(&self, y: i64, start: i64, end: i64) -> Option<Span> {
+        if y < i64::from(self.top) || y > i64::from(self.bottom) {
+            return None;
+        }
+        let start = start.max(i64::from(self.left));
+        let end = end.min(i64::from(self.right));
+        if start > end {
+            None
+        } else {
+            Some((y as i32, start as i32, end as i32))
+        }
+    }
+
+    /// Rows in both the bounds and from start to end
+    fn rows(&self, start: i64, end: i64) -> std::ops::RangeInclusive<i64> {
+        start.max(i64::from(self.top))..=end.min(i64::from(self.bottom))
+    }
+}
+
+/// Rows of a rectangle outline with its top left at x, y
+pub fn rect(x: i32, y: i32, w: i32, h: i32, bounds: Bounds) -> Vec<Span> {
+    if w <= 0 || h <= 0 {
+        return Vec::new();
+    }
+    let (x, y) = (i64::from(x), i64::from(y));
+    let (right, bottom) = (x + i64::from(w) - 1, y + i64::from(h) - 1);
+    let mut spans = Vec::new();
+    for row in bounds.rows(y, bottom) {
+        if row == y || row == bottom {
+            spans.extend(bounds.clip_span(row, x, right));
+        } else {
+            spans.extend(bounds.clip_span(row, x, x));
+            if right != x {
+                spans.extend(bounds.clip_span(row, right, right));
+            }
+        }
+    }
+    spans
+}
+
+/// Returns the half width of the row dy rows from the centre of an ellipse.
+/// A pixel is inside if it is (roughly) within half a pixel of the real ellipse
+fn ellipse_half_width(rx: i64, ry: i64, dy: i64) -> i64 {
+    if ry == 0 {
+        return rx;
+    }
+    let (rx, ry, dy) = (i128::from(rx), i128::from(ry), i128::from(dy));
+    // (x / rx)^2 + (y / ry)^2 <= 1 + 1 / r, multiplied out so it works for a radius of 0
+    let limit = rx * rx * ry * ry + rx * ry * (rx + ry) / 2;
+    let max_square = (limit - dy * dy * rx * rx) / (ry * ry);
+    if max_square < 0 {
+        return 0;
+    }
+    // integer square root, correcting the rounding of the float square root
+    let mut half_width = (max_square as f64).sqrt() as i128;
+    while half_width * half_width > max_square {
+        half_width -= 1;
+    }
+    while (half_width + 1) * (half_width + 1) <= max_square {
+        half_width += 1;
+    }
+    half_width.min(rx) as i64
+}
+
+/// Rows of a filled ellipse with its centre at cx, cy
+pub fn fill_ellipse(cx: i32, cy: i32, rx: i32, ry: i32, bounds: Bounds) -> Vec<Span> {
+    let (cx, cy, rx, ry) = (i64::from(cx), i64::from(cy), i64::from(rx), i64::from(ry));
+    bounds
+        .rows(cy - ry, cy + ry)
+        .filter_map(|y| {
+            let half_width = ellipse_half_width(rx, ry, (y - cy).abs());
+            bounds.clip_span(y, cx - half_width, cx + half_width)
+        })
+        .collect()
+}
+
+/// Outline of an ellipse with its centre at cx, cy. The outline is the pixels of
+/// the filled ellipse that are not surrounded by the filled ellipse
+pub fn ellipse(cx: i32, cy: i32, rx: i32, ry: i32, bounds: Bounds) -> Vec<Span> {
+    let (cx, cy, rx, ry) = (i64::from(cx), i64::from(cy), i64::from(rx), i64::from(ry));
+    let mut spans = Vec::new();
+    for y in bounds.rows(cy - ry, cy + ry) {
+        let dy = (y - cy).abs();
+        let half_width = ellipse_half_width(rx, ry, dy);
+        // the row further from the centre covers the inside of this row
+        let inner = if dy == ry {
+            0
+        } else {
+            let next = ellipse_half_width(rx, ry, dy + 1);
+            if next < half_width {
+                next + 1
+            } else {
+                half_width
+            }
+        };
+        if inner == 0 {
+            spans.extend(bounds.clip_span(y, cx - half_width, cx + half_width));
+        } else {
+            spans.extend(bounds.clip_span(y, cx - half_width, cx - inner));
+            spans.extend(bounds.clip_span(y, cx + inner, cx + half_width));
+        }
+    }
+    spans
+}
+
+/// Cuts off the parts of a line outside of the bounds (using the Liang-Barsky algorithm).
+/// Returns None if the whole line is outside
+fn clip_line(x0: i32, y0: i32, x1: i32, y1: i32, bounds: Bounds) -> Option<[i32; 4]> {
+    if bounds.contains((x0, y0)) && bounds.contains((x1, y1)) {
+        return Some([x0, y0, x1, y1]);
+    }
+    let (x0, y0, x1, y1) = (f64::from(x0), f64::from(y0), f64::from(x1), f64::from(y1));
+    let (dx, dy) = (x1 - x0, y1 - y0);
+    let edges = [
+        (-dx, x0 - f64::from(bounds.left)),
+        (dx, f64::from(bounds.right) - x0),
+        (-dy, y0 - f64::from(bounds.top)),
+        (dy, f64::from(bounds.bottom) - y0),
+    ];
+    // the part of the line that is kept, as fractions of its length
+    let (mut start, mut end) = (0.0, 1.0);
+    for &(p, q) in &edges {
+        if p == 0.0 {
+            if q < 0.0 {
+                return None;
+            }
+        } else if p < 0.0 {
+            start = (q / p).max(start);
+        } else {
+            end = (q / p).min(end);
+        }
+    }
+    if start > end {
+        return None;
+    }
+    Some([
+        (x0 + start * dx).round() as i32,
+        (y0 + start * dy).round() as i32,
+        (x0 + end * dx).round() as i32,
+        (y0 + end * dy).round() as i32,
+    ])
+}
+
+/// Uses Bresenham's line algorithm, on the part of the line inside the bounds
+pub fn line(x0: i32, y0: i32, x1: i32, y1: i32, bounds: Bounds) -> Vec<(i32, i32)> {
+    let mut points = Vec::new();
+    let [mut x0, mut y0, x1, y1] = match clip_line(x0, y0, x1, y1, bounds) {
+        Some(line) => line,
+        None => return points,
+    };
+    let dx = (x1 - x0).abs();
+    let step_x = if x0 < x1 { 1 } else { -1 };
+    let dy = -(y1 - y0).abs();
+    let step_y = if y0 < y1 { 1 } else { -1 };
+    let mut error = dx + dy;
+    loop {
+        points.push((x0, y0));
+        if x0 == x1 && y0 == y1 {
+            break;
+        }
+        let error_2 = 2 * error;
+        if error_2 >= dy {
+            error += dy;
+            x0 += step_x
+        }
+        if error_2 <= dx {
+            error += dx;
+            y0 += step_y;
+        }
+    }
+    points
+}
+
+/// Outline of a triangle. Each pixel is only included once
+pub fn triangle(points: [(i32, i32); 3], bounds: Bounds) -> Vec<(i32, i32)> {
+    let [a, b, c] = points;
+    let mut outline = line(a.0, a.1, b.0, b.1, bounds);
+    outline.extend(line(b.0, b.1, c.0, c.1, bounds));
+    outline.extend(line(c.0, c.1, a.0, a.1, bounds));
+    outline.sort_unstable();
+    outline.dedup();
+    outline
+}
+
+/// Rows of a filled triangle, which covers the outline of the triangle
+pub fn fill_triangle(mut points: [(i32, i32); 3], bounds: Bounds) -> Vec<Span> {
+    points.sort_by_key(|&(_, y)| y);
+    let to_i64 = |(x, y): (i32, i32)| (i64::from(x), i64::from(y));
+    let (top, middle, bottom) = (to_i64(points[0]), to_i64(points[1]), to_i64(points[2]));
+    let rows = bounds.rows(top.1, bottom.1);
+    if rows.is_empty() {
+        return Vec::new();
+    }
+    let first_row = *rows.start();
+
+    // x where the edge from a to b crosses row y. a and b must be on different rows.
+    // The product of two i32 distances does not always fit in an i64, but the result does
+    let edge_x = |(ax, ay): (i64, i64), (bx, by): (i64, i64), y: i64| {
+        ax + (i128::from(bx - ax) * i128::from(y - ay) / i128::from(by - ay)) as i64
+    };
+    let mut spans: Vec<(i64, i64)> = rows
+        .clone()
+        .map(|y| {
+            if top.1 == bottom.1 {
+                // every point is on one row
+                return (
+                    top.0.min(middle.0).min(bottom.0),
+                    top.0.max(middle.0).max(bottom.0),
+                );
+            }
+            let long_x = edge_x(top, bottom, y);
+            let short_x = if y < middle.1 {
+                edge_x(top, middle, y)
+            } else if middle.1 != bottom.1 {
+                edge_x(middle, bottom, y)
+            } else {
+                middle.0
+            };
+            (long_x.min(short_x), long_x.max(short_x))
+        })
+        .collect();
+    // the rows do not always reach the outline drawn by Bresenham's line algorithm,
+    // but the triangle is convex, so the rows can be stretched to cover it
+    for (x, y) in triangle(points, bounds) {
+        let span = &mut spans[(i64::from(y) - first_row) as usize];
+        *span = (span.0.min(i64::from(x)), span.1.max(i64::from(x)));
+    }
+    rows.zip(spans)
+        .filter_map(|(y, (start, end))| bounds.clip_span(y, start, end))
+        .collect()
+}
+
+/// Returns the pixels connected to x, y (not diagonally) that have the same color as it
+/// and are inside the bounds. colors holds the color of every pixel of a screen that is
+/// w pixels wide, one row after another, and the bounds must be inside the screen
+pub fn flood_fill(colors: &[u32], w: u32, x: i32, y: i32, bounds: Bounds) -> Vec<(i32, i32)> {
+    if !bounds.contains((x, y)) {
+        return Vec::new();
+    }
+    let index = |x: i32, y: i32| y as usize * w as usize + x as usize;
+    let target = colors[index(x, y)];
+    let mut filled = vec![false; colors.len()];
+    let mut points = Vec::new();
+    let mut stack = vec![(x, y)];
+    filled[index(x, y)] = true;
+    while let Some((x, y)) = stack.pop() {
+        points.push((x, y));
+        for &(nx, ny) in &[(x - 1, y), (x + 1, y), (x, y - 1), (x, y + 1)] {
+            if bounds.contains((nx, ny))
+                && !filled[index(nx, ny)]
+                && colors[index(nx, ny)] == target
+            {
+                filled[index(nx, ny)] = true;
+                stack.push((nx, ny));
+            }
+        }
+    }
+    points
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn screen() -> Bounds {
+        Bounds::from_rect(0, 0, 64, 32)
+    }
+
+    #[test]
+    fn bounds_from_rect_includes_both_edges() {
+        let bounds = Bounds::from_rect(2, 3, 4, 5);
+        assert_eq!(
+            (bounds.left, bounds.top, bounds.right, bounds.bottom),
+            (2, 3, 5, 7)
+        );
+        assert_eq!((bounds.width(), bounds.height()), (4, 5));
+        assert!(Bounds::from_rect(0, 0, 0, 5).is_empty());
+        assert!(Bounds::from_rect(0, 0, 5, -1).is_empty());
+    }
+
+    #[test]
+    fn rect_is_an_outline() {
+        assert_eq!(
+            rect(1, 1, 3, 3, screen()),
+            vec![(1, 1, 3), (2, 1, 1), (2, 3, 3), (3, 1, 3)]
+        );
+        assert_eq!(rect(5, 5, 1, 1, screen()), vec![(5, 5, 5)]);
+    }
+
+    #[test]
+    fn line_covers_both_ends() {
+        assert_eq!(
+            line(0, 0, 3, 1, screen()),
+            vec![(0, 0), (1, 0), (2, 1), (3, 1)]
+        );
+        assert_eq!(line(5, 5, 5, 5, screen()), vec![(5, 5)]);
+    }
+
+    #[test]
+    fn fill_ellipse_is_symmetric() {
+        let spans = fill_ellipse(10, 10, 3, 2, screen());
+        assert_eq!(spans.len(), 5);
+        assert_eq!(spans[2], (10, 7, 13));
+        assert_eq!(spans[0].1, spans[4].1);
+        for &(y, start, end) in &spans {
+            assert_eq!(10 - start, end - 10, "row {}", y);
+        }
+    }
+
+    #[test]
+    fn ellipse_outline_is_inside_the_filled_ellipse() {
+        let filled = fill_ellipse(20, 15, 7, 4, screen());
+        for (y, start, end) in ellipse(20, 15, 7, 4, screen()) {
+            let row = filled.iter().find(|span| span.0 == y).unwrap();
+            assert!(row.1 <= start && end <= row.2, "row {}", y);
+        }
+        assert_eq!(fill_ellipse(3, 3, 0, 0, screen()), vec![(3, 3, 3)]);
+    }
+
+    #[test]
+    fn fill_triangle_covers_its_outline() {
+        let points = [(1, 1), (10, 4), (3, 9)];
+        let spans = fill_triangle(points, screen());
+        for (x, y) in triangle(points, screen()) {
+            assert!(
+                spans
+                    .iter()
+                    .any(|&(row, start, end)| row == y && start <= x && x <= end),
+                "({}, {}) is not filled",
+                x,
+                y
+            );
+        }
+    }
+
+    #[test]
+    fn flood_fill_does_not_cross_other_colors() {
+        #[rustfmt::skip]
+        let colors = [
+            1, 1, 2, 1,
+            1, 2, 1, 1,
+            2, 1, 1, 1,
+        ];
+        let mut filled = flood_fill(&colors, 4, 0, 0, Bounds::from_rect(0, 0, 4, 3));
+        filled.sort();
+        assert_eq!(filled, vec![(0, 0), (0, 1), (1, 0)]);
+    }
+
+    #[test]
+    fn bounds_from_rect_does_not_overflow() {
+        let bounds = Bounds::from_rect(i32::MAX, 0, i32::MAX, 1);
+        assert_eq!((bounds.left, bounds.right), (i32::MAX, i32::MAX));
+    }
+
+    #[test]
+    fn rect_is_clipped() {
+        // the top row and left column are off the screen
+        assert_eq!(rect(-2, -2, 5, 4, screen()), vec![(0, 2, 2), (1, 0, 2)]);
+        assert!(rect(0, 0, -3, 4, screen()).is_empty());
+    }
+
+    #[test]
+    fn huge_line_is_clipped() {
+        let points = line(-100_000, 10, 100_000, 10, screen());
+        assert_eq!(points.len(), 64);
+        assert!(points.iter().all(|&point| screen().contains(point)));
+    }
+
+    #[test]
+    fn fill_triangle_with_extreme_points() {
+        let spans = fill_triangle(
+            [(i32::MIN, i32::MIN), (0, 0), (i32::MAX, i32::MAX)],
+            screen(),
+        );
+        assert_eq!(spans.len(), 32);
+        for &(y, start, end) in &spans {
+            assert!(screen().contains((start, y)) && screen().contains((end, y)));
+        }
+    }
+}
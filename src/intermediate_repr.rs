@@ -2,11 +2,19 @@
 //! This flattens all control structures (for, if, while) into goto and jump instructions.
 //! Expression trees are kept in their original parsed state.
 
-use crate::ast::{AssignTarget, Block, DataBlock, DataDef, Expr, Line};
+use crate::ast::{AssignTarget, Block, DataBlock, DataDef, Expr, Line, LineData, StructField};
+use crate::constants::{eval_const_expr, ConstTable};
+use crate::error::{Error, IResult};
+use crate::macros::{defined_labels, Macro, MAX_EXPANSION_DEPTH};
+use crate::util::append_u32;
 use std::borrow::Cow;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 
+/// Start of the names of labels generated by control structures.
+/// Uses `$` because this char is not available in user label names, to avoid collision
+pub const INTERNAL_LABEL_PREFIX: &str = "$internal_";
+
 pub type IntermediateBlock<'a> = Vec<IntermediateLine<'a>>;
 pub type IntermediateBlockSlice<'a> = [IntermediateLine<'a>];
 
@@ -22,134 +30,390 @@ pub enum IntermediateLine<'a> {
     // name, args, is_saveargs
     FunDeclaration(Cow<'a, str>, Vec<&'a str>, bool),
     FunReturn,
+    VarDeclaration(Vec<&'a str>),
+    /// Removed by constants::substitute_constants before the program runs
+    Const(&'a str, Expr<'a>),
+    /// Removed by structs::lower_field_access before the program runs
+    Struct(&'a str, Vec<StructField<'a>>),
     Expr(Expr<'a>),
 }
 
-/// Converts parsed AST lines to intermediate representaiton lines
-pub fn to_intermediate_repr(ast: Block<'_>) -> IntermediateBlock<'_> {
-    // label counter is used for generating internal labels
-    // (counts up so each label is uniquely named)
-    let mut label_counter = 0u32;
-    convert_block(ast, &mut label_counter)
+/// Converts parsed AST lines to intermediate representaiton lines, expanding macros
+pub fn to_intermediate_repr(ast: Block<'_>) -> IResult<IntermediateBlock<'_>> {
+    let mut converter = Converter {
+        label_counter: 0,
+        module_path: Vec::new(),
+        functions: HashSet::new(),
+        labels: HashSet::new(),
+        macros: HashMap::new(),
+        label_renames: Vec::new(),
+    };
+    // functions, labels and macros can be used before they are defined, so find them all first
+    converter.collect_names(&ast)?;
+    converter.convert_block(ast)
 }
 
-fn convert_block<'a>(block: Block<'a>, counter: &mut u32) -> IntermediateBlock<'a> {
-    block
-        .into_iter()
-        .flat_map(|line| convert_line(line.line, counter))
-        .collect()
+/// Converts AST lines to IR lines, giving functions and labels inside modules their qualified names
+struct Converter<'a> {
+    // label counter is used for generating internal labels
+    // (counts up so each label is uniquely named)
+    label_counter: u32,
+    /// Modules around the line being converted, outermost first
+    module_path: Vec<&'a str>,
+    /// Qualified names of every function
+    functions: HashSet<String>,
+    /// Qualified names of every code label
+    labels: HashSet<String>,
+    macros: HashMap<&'a str, Macro<'a>>,
+    /// New names for the labels defined in each macro being expanded, innermost expansion last
+    label_renames: Vec<HashMap<&'a str, Cow<'a, str>>>,
 }
 
-fn convert_line<'a>(line: Line<'a>, counter: &mut u32) -> IntermediateBlock<'a> {
-    let mut block = Vec::new();
-    match line {
-        Line::Assign(t, e) => block.push(IntermediateLine::Assign(t, e)),
-        Line::Goto(l) => block.push(IntermediateLine::Goto(Cow::from(l))),
-        Line::Label(l) => block.push(IntermediateLine::Label(Cow::from(l))),
-
-        Line::If(test_expr, then_block, else_block) => {
-            let else_label = next_label_name(counter);
-            // If condition is false, skip the if block body and jump to else
-            block.push(IntermediateLine::JumpFalse(test_expr, else_label.clone()));
-            block.extend(convert_block(then_block, counter));
-            if let Some(else_block) = else_block {
-                //there is an else block
-                let exit_label = next_label_name(counter);
-                // if falling through from the if block, jump over the else block to the exit
-                block.push(IntermediateLine::Goto(exit_label.clone()));
-                block.push(IntermediateLine::Label(else_label));
-                block.extend(convert_block(else_block, counter));
-                block.push(IntermediateLine::Label(exit_label));
-            } else {
-                //no else block
-                block.push(IntermediateLine::Label(else_label));
+impl<'a> Converter<'a> {
+    fn collect_names(&mut self, block: &[LineData<'a>]) -> IResult<()> {
+        for line in block {
+            match &line.line {
+                Line::FunDeclaration(name, _, body, _) => {
+                    self.functions.insert(self.qualify(name).into_owned());
+                    self.collect_names(body)?;
+                }
+                Line::Label(name) => {
+                    self.labels.insert(self.qualify(name).into_owned());
+                }
+                Line::If(_, then_block, else_block) => {
+                    self.collect_names(then_block)?;
+                    if let Some(else_block) = else_block {
+                        self.collect_names(else_block)?;
+                    }
+                }
+                Line::For(.., body) | Line::While(_, body) => self.collect_names(body)?,
+                Line::Module(name, body) => {
+                    self.module_path.push(name);
+                    self.collect_names(body)?;
+                    self.module_path.pop();
+                }
+                // labels in a macro are renamed for each expansion, so are not collected here
+                Line::Macro(name, params, body) => {
+                    let macro_def = Macro {
+                        params: params.clone(),
+                        body: body.clone(),
+                    };
+                    if self.macros.insert(name, macro_def).is_some() {
+                        return Err(Error::MacroRedefinition(name.to_string()));
+                    }
+                }
+                _ => {}
             }
         }
+        Ok(())
+    }
 
-        Line::For(counter_variable, start, end, body) => {
-            let start_label = next_label_name(counter);
-            let exit_label = next_label_name(counter);
-            //TODO macro for multiple pushes (does it exist?)
-            block.extend(vec![
-                IntermediateLine::Assign(AssignTarget::Var(counter_variable), start),
-                IntermediateLine::Label(start_label.clone()),
-                // if outside the bounds of the for loop (counter_variable >= end),
-                // jump out of loop
-                IntermediateLine::JumpFalse(
-                    Expr::Lt(Box::new(Expr::Var(counter_variable)), Box::new(end)),
-                    exit_label.clone(),
-                ),
-            ]);
-            block.extend(convert_block(body, counter));
-            block.extend(vec![
-                // counter_variable = counter_variable + 1
-                IntermediateLine::Assign(
-                    AssignTarget::Var(counter_variable),
-                    Expr::Add(
-                        Box::new(Expr::Var(counter_variable)),
-                        Box::new(Expr::Literal(1)),
-                    ),
-                ),
-                IntermediateLine::Goto(start_label),
-                IntermediateLine::Label(exit_label),
-            ])
+    /// Name of a function or label defined in the current module
+    fn qualify(&self, name: &'a str) -> Cow<'a, str> {
+        if self.module_path.is_empty() {
+            Cow::from(name)
+        } else {
+            Cow::from(format!("{}.{}", self.module_path.join("."), name))
         }
+    }
+
+    /// Finds what a name used in the current module refers to.
+    /// Names in the innermost module are preferred, then its parent modules, and then global names.
+    fn resolve(&self, name: &'a str, names: &HashSet<String>) -> Cow<'a, str> {
+        (1..=self.module_path.len())
+            .rev()
+            .map(|depth| format!("{}.{}", self.module_path[..depth].join("."), name))
+            .find(|qualified| names.contains(qualified))
+            .map_or(Cow::from(name), Cow::from)
+    }
+
+    /// New name of a label defined in the macro being expanded
+    fn renamed_label(&self, name: &'a str) -> Option<Cow<'a, str>> {
+        let renames = self.label_renames.last()?;
+        renames.get(name).cloned()
+    }
+
+    /// Name of a label defined in the current module, or in the macro being expanded
+    fn label_name(&self, name: &'a str) -> Cow<'a, str> {
+        self.renamed_label(name)
+            .unwrap_or_else(|| self.qualify(name))
+    }
+
+    /// Finds the label a goto in the current module or macro jumps to
+    fn resolve_label(&self, name: &'a str) -> Cow<'a, str> {
+        self.renamed_label(name)
+            .unwrap_or_else(|| self.resolve(name, &self.labels))
+    }
 
-        Line::While(condition, body) => {
-            let start_label = next_label_name(counter);
-            let exit_label = next_label_name(counter);
-            block.push(IntermediateLine::Label(start_label.clone()));
-            // if while condition is false, jump out of loop
-            block.push(IntermediateLine::JumpFalse(condition, exit_label.clone()));
-            block.extend(convert_block(body, counter));
-            block.push(IntermediateLine::Goto(start_label));
-            block.push(IntermediateLine::Label(exit_label));
+    /// Resolves the names of all functions called in expr
+    fn resolve_calls(&self, mut expr: Expr<'a>) -> Expr<'a> {
+        if !self.module_path.is_empty() {
+            expr.visit_mut(&mut |e| {
+                if let Expr::FunCall(name, _) = e {
+                    if let Cow::Borrowed(borrowed) = *name {
+                        *name = self.resolve(borrowed, &self.functions);
+                    }
+                }
+            });
         }
+        expr
+    }
 
-        Line::FunDeclaration(name, args, body, is_saveargs) => {
-            block.push(IntermediateLine::FunDeclaration(
-                Cow::from(name),
-                args,
-                is_saveargs,
-            ));
-            block.extend(convert_block(body, counter));
-            block.push(IntermediateLine::FunReturn);
+    fn resolve_target(&self, target: AssignTarget<'a>) -> AssignTarget<'a> {
+        match target {
+            AssignTarget::Var(name) => AssignTarget::Var(name),
+            AssignTarget::Field(base, field) => AssignTarget::Field(base, field),
+            AssignTarget::Index(name, e) => AssignTarget::Index(name, self.resolve_calls(e)),
+            AssignTarget::Addr(e) => AssignTarget::Addr(self.resolve_calls(e)),
+            AssignTarget::ByteAddr(e) => AssignTarget::ByteAddr(self.resolve_calls(e)),
         }
+    }
 
-        Line::Expr(e) => block.push(IntermediateLine::Expr(e)),
+    fn convert_block(&mut self, block: Block<'a>) -> IResult<IntermediateBlock<'a>> {
+        let mut converted = Vec::new();
+        for line in block {
+            converted.extend(self.convert_line(line.line)?);
+        }
+        Ok(converted)
     }
-    block
-}
 
-/// Generates and returns the name of a new internal label
-/// (increments counter to create the name)
-fn next_label_name<'a, 'b>(counter: &'a mut u32) -> Cow<'b, str> {
-    //TODO maybe pass in description for debug puposes (eg. for_start / for_exit)
-    //uses `$` because this char is not available in user label names, to avoid collision
-    let label_name = format!("$internal_{}", counter);
-    *counter += 1;
-    Cow::from(label_name)
+    fn convert_line(&mut self, line: Line<'a>) -> IResult<IntermediateBlock<'a>> {
+        let mut block = Vec::new();
+        match line {
+            Line::Assign(t, e) => block.push(IntermediateLine::Assign(
+                self.resolve_target(t),
+                self.resolve_calls(e),
+            )),
+            Line::Goto(l) => block.push(IntermediateLine::Goto(self.resolve_label(l))),
+            Line::Label(l) => block.push(IntermediateLine::Label(self.label_name(l))),
+
+            Line::If(test_expr, then_block, else_block) => {
+                let else_label = self.next_label_name();
+                // If condition is false, skip the if block body and jump to else
+                block.push(IntermediateLine::JumpFalse(
+                    self.resolve_calls(test_expr),
+                    else_label.clone(),
+                ));
+                block.extend(self.convert_block(then_block)?);
+                if let Some(else_block) = else_block {
+                    //there is an else block
+                    let exit_label = self.next_label_name();
+                    // if falling through from the if block, jump over the else block to the exit
+                    block.push(IntermediateLine::Goto(exit_label.clone()));
+                    block.push(IntermediateLine::Label(else_label));
+                    block.extend(self.convert_block(else_block)?);
+                    block.push(IntermediateLine::Label(exit_label));
+                } else {
+                    //no else block
+                    block.push(IntermediateLine::Label(else_label));
+                }
+            }
+
+            Line::For(counter_variable, start, end, body) => {
+                let start_label = self.next_label_name();
+                let exit_label = self.next_label_name();
+                //TODO macro for multiple pushes (does it exist?)
+                block.extend(vec![
+                    IntermediateLine::Assign(
+                        AssignTarget::Var(counter_variable),
+                        self.resolve_calls(start),
+                    ),
+                    IntermediateLine::Label(start_label.clone()),
+                    // if outside the bounds of the for loop (counter_variable >= end),
+                    // jump out of loop
+                    IntermediateLine::JumpFalse(
+                        Expr::Lt(
+                            Box::new(Expr::Var(counter_variable)),
+                            Box::new(self.resolve_calls(end)),
+                        ),
+                        exit_label.clone(),
+                    ),
+                ]);
+                block.extend(self.convert_block(body)?);
+                block.extend(vec![
+                    // counter_variable = counter_variable + 1
+                    IntermediateLine::Assign(
+                        AssignTarget::Var(counter_variable),
+                        Expr::Add(
+                            Box::new(Expr::Var(counter_variable)),
+                            Box::new(Expr::Literal(1)),
+                        ),
+                    ),
+                    IntermediateLine::Goto(start_label),
+                    IntermediateLine::Label(exit_label),
+                ])
+            }
+
+            Line::While(condition, body) => {
+                let start_label = self.next_label_name();
+                let exit_label = self.next_label_name();
+                block.push(IntermediateLine::Label(start_label.clone()));
+                // if while condition is false, jump out of loop
+                block.push(IntermediateLine::JumpFalse(
+                    self.resolve_calls(condition),
+                    exit_label.clone(),
+                ));
+                block.extend(self.convert_block(body)?);
+                block.push(IntermediateLine::Goto(start_label));
+                block.push(IntermediateLine::Label(exit_label));
+            }
+
+            Line::FunDeclaration(name, args, body, is_saveargs) => {
+                block.push(IntermediateLine::FunDeclaration(
+                    self.qualify(name),
+                    args,
+                    is_saveargs,
+                ));
+                block.extend(self.convert_block(body)?);
+                block.push(IntermediateLine::FunReturn);
+            }
+
+            Line::Module(name, body) => {
+                self.module_path.push(name);
+                block.extend(self.convert_block(body)?);
+                self.module_path.pop();
+            }
+
+            Line::MacroCall(name, args) => {
+                if self.label_renames.len() >= MAX_EXPANSION_DEPTH {
+                    return Err(Error::MacroRecursionLimit(name.to_string()));
+                }
+                let body = self
+                    .macros
+                    .get(name)
+                    .ok_or_else(|| Error::MacroNotFound(name.to_string()))?
+                    .expand(name, &args)?;
+                // give the macro's labels new names, so it can be expanded more than once
+                let renames = defined_labels(&body)
+                    .into_iter()
+                    .map(|label| (label, self.next_label_name()))
+                    .collect();
+                self.label_renames.push(renames);
+                let expanded = self.convert_block(body);
+                self.label_renames.pop();
+                block.extend(expanded?);
+            }
+            // already collected by collect_names
+            Line::Macro(..) => {}
+
+            Line::VarDeclaration(names) => block.push(IntermediateLine::VarDeclaration(names)),
+            Line::Struct(name, fields) => block.push(IntermediateLine::Struct(name, fields)),
+            Line::Const(name, e) => {
+                block.push(IntermediateLine::Const(name, self.resolve_calls(e)))
+            }
+            Line::Expr(e) => block.push(IntermediateLine::Expr(self.resolve_calls(e))),
+        }
+        Ok(block)
+    }
+
+    /// Generates and returns the name of a new internal label
+    /// (increments counter to create the name)
+    fn next_label_name<'b>(&mut self) -> Cow<'b, str> {
+        //TODO maybe pass in description for debug puposes (eg. for_start / for_exit)
+        let label_name = format!("{}{}", INTERNAL_LABEL_PREFIX, self.label_counter);
+        self.label_counter += 1;
+        Cow::from(label_name)
+    }
 }
 
-/// Flatten data block so all of the data is in a byte vec.
+/// Flatten data blocks so all of the data is in a byte vec, with each block following the last.
 /// Labels are in a hashmap mapping them to the index of data they're pointing to
-pub fn convert_data_segment(data: DataBlock<'_>) -> DataSegment<'_> {
-    let mut map = HashMap::new();
-    let mut data_vec = Vec::new();
+pub fn convert_data_segment<'a>(
+    data: Vec<DataBlock<'a>>,
+    constants: &ConstTable<'a>,
+) -> IResult<DataSegment<'a>> {
+    let mut converter = DataConverter {
+        constants,
+        data: Vec::new(),
+        labels: HashMap::new(),
+        fixups: Vec::new(),
+    };
+    for data_def in data.iter().flatten() {
+        converter.convert(data_def)?;
+    }
+
+    // all labels are known now, so u32s referring to them can be filled in
+    let DataConverter {
+        mut data,
+        labels,
+        fixups,
+        ..
+    } = converter;
+    for (index, expr) in fixups {
+        let mut expr = expr.clone();
+        expr.try_visit_mut(&mut |e| {
+            if let Expr::VarAddress(name) = e {
+                let addr = labels
+                    .get(name)
+                    .ok_or_else(|| Error::LabelNotFound(name.to_string()))?;
+                *e = Expr::Literal(*addr as u32);
+            }
+            Ok(())
+        })?;
+        let value = eval_const_expr(&expr, constants)?;
+        data[index..index + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    Ok(DataSegment(data, labels))
+}
+
+/// Flattens data definitions into bytes.
+struct DataConverter<'a, 'b> {
+    constants: &'b ConstTable<'a>,
+    data: Vec<u8>,
+    labels: HashMap<&'a str, usize>,
+    /// u32s that may refer to labels after them, so are filled in once every label is known
+    fixups: Vec<(usize, &'b Expr<'a>)>,
+}
+
+impl<'a, 'b> DataConverter<'a, 'b> {
+    fn add_label(&mut self, name: &'a str) -> IResult<()> {
+        // data.len() will point to the next data byte when it is appended
+        if self.labels.insert(name, self.data.len()).is_some() {
+            return Err(Error::DataLabelRedefinition(name.to_string()));
+        }
+        Ok(())
+    }
 
-    for data_def in data {
+    fn convert(&mut self, data_def: &'b DataDef<'a>) -> IResult<()> {
         match data_def {
-            DataDef::Label(name) => {
-                // data_vec.len() will point to the next data byte when it is appended
-                map.insert(name, data_vec.len());
+            DataDef::Label(name) => self.add_label(name)?,
+            DataDef::Bytes(bytes) => {
+                self.data.extend(bytes);
             }
-            DataDef::Bytes(vec) => {
-                data_vec.extend(vec);
+            DataDef::U32s(values) => {
+                for value in values {
+                    self.fixups.push((self.data.len(), value));
+                    append_u32(&mut self.data, 0);
+                }
             }
+            DataDef::Zeros(count) => {
+                let count = eval_const_expr(count, self.constants)?;
+                self.data.resize(self.data.len() + count as usize, 0);
+            }
+            DataDef::Repeat(count, repeated) => {
+                for _ in 0..eval_const_expr(count, self.constants)? {
+                    self.convert(repeated)?;
+                }
+            }
+            DataDef::Align(alignment) => {
+                let alignment = eval_const_expr(alignment, self.constants)? as usize;
+                if alignment > 1 {
+                    let padding = (alignment - self.data.len() % alignment) % alignment;
+                    self.data.resize(self.data.len() + padding, 0);
+                }
+            }
+            DataDef::Array(name, width, length) => {
+                self.add_label(name)?;
+                let length = eval_const_expr(length, self.constants)?;
+                let size = length as usize * width.size() as usize;
+                self.data.resize(self.data.len() + size, 0);
+            }
+            // already evaluated by constants::collect_constants and structs::collect_structs
+            DataDef::Const(..) | DataDef::Struct(..) => {}
         }
+        Ok(())
     }
-
-    DataSegment(data_vec, map)
 }
 
 impl fmt::Display for IntermediateLine<'_> {
@@ -163,6 +427,9 @@ impl fmt::Display for IntermediateLine<'_> {
                 write!(f, "{}func {}{:?}", if *s { "saveargs " } else { "" }, n, a)
             }
             IntermediateLine::FunReturn => write!(f, "return"),
+            IntermediateLine::VarDeclaration(names) => write!(f, "var {}", names.join(", ")),
+            IntermediateLine::Const(name, e) => write!(f, "const {} = {:?}", name, e),
+            IntermediateLine::Struct(name, fields) => write!(f, "struct {} {:?}", name, fields),
             IntermediateLine::Expr(e) => write!(f, "{:?}", e),
         }
     }
@@ -178,3 +445,54 @@ pub fn display_intermediate_block(block: &IntermediateBlockSlice) -> String {
         })
         .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::DataSegment;
+    use crate::error::Error;
+    use crate::lower_source;
+
+    /// The bytes of a data section and the address of a label in it
+    fn data(source: &str, label: &str) -> (Vec<u8>, Option<usize>) {
+        let source = format!(".DATA\n{}\n.CODE\nexit()", source);
+        let (_, DataSegment(bytes, labels)) = lower_source(&source, false).unwrap();
+        (bytes, labels.get(label).copied())
+    }
+
+    #[test]
+    fn u32_lists_are_little_endian() {
+        assert_eq!(
+            data("1, 0x20, 3", "").0,
+            vec![1, 0, 0, 0, 0x20, 0, 0, 0, 3, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn repeat_and_align() {
+        assert_eq!(
+            data("repeat 3 7.\nalign 4\nrepeat (1 + 1) 0x0102", "").0,
+            vec![7, 7, 7, 0, 2, 1, 0, 0, 2, 1, 0, 0]
+        );
+    }
+
+    #[test]
+    fn label_expressions_can_use_later_labels() {
+        let (bytes, end) = data("start:\n&end - &start\n{AABB}\nend:\n0.", "end");
+        assert_eq!(end, Some(6));
+        assert_eq!(bytes, vec![6, 0, 0, 0, 0xAA, 0xBB, 0]);
+    }
+
+    #[test]
+    fn strings_with_escapes() {
+        assert_eq!(
+            data("z\"a\\n\\x41\"\np\"hi\"", "").0,
+            vec![b'a', b'\n', b'A', 0, 2, 0, 0, 0, b'h', b'i']
+        );
+    }
+
+    #[test]
+    fn duplicate_data_labels_are_an_error() {
+        let result = lower_source(".DATA\na:\n1.\na:\n2.\n.CODE\nexit()", false);
+        assert!(matches!(result, Err(Error::DataLabelRedefinition(label)) if label == "a"));
+    }
+}
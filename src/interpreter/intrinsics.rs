@@ -2,6 +2,7 @@
 
 use super::InterpreterState;
 use crate::error::{Error, IResult};
+use crate::graphics::{check_sprite_size, palette_row_len, PALETTE_COLORS};
 use lazy_static::lazy_static;
 use rand::Rng;
 use std::char;
@@ -114,6 +115,46 @@ lazy_static! {
             flush_stdout()?;
             Ok(0)
         }),
+        intrinsic!(memcpy, [3], (args, state) => {
+            let (dst, src, len) = (args[0] as usize, args[1] as usize, args[2] as usize);
+            state.check_memory_range(src, len)?;
+            state.check_memory_range(dst, len)?;
+            if src < dst + len && dst < src + len {
+                return Err(Error::OverlappingCopy { dst, src, len });
+            }
+            state.data.copy_within(src..src + len, dst);
+            Ok(0)
+        }),
+        intrinsic!(memmove, [3], (args, state) => {
+            let (dst, src, len) = (args[0] as usize, args[1] as usize, args[2] as usize);
+            state.check_memory_range(src, len)?;
+            state.check_memory_range(dst, len)?;
+            // copy_within handles overlapping ranges
+            state.data.copy_within(src..src + len, dst);
+            Ok(0)
+        }),
+        intrinsic!(memset, [3], (args, state) => {
+            let (dst, len) = (args[0] as usize, args[2] as usize);
+            state.check_memory_range(dst, len)?;
+            // value is truncated to a byte, as with `->` to a byte address
+            for byte in &mut state.data[dst..dst + len] {
+                *byte = args[1] as u8;
+            }
+            Ok(0)
+        }),
+        intrinsic!(memcmp, [3], (args, state) => {
+            let (a, b, len) = (args[0] as usize, args[1] as usize, args[2] as usize);
+            state.check_memory_range(a, len)?;
+            state.check_memory_range(b, len)?;
+            // difference of the first mismatched bytes (wrapping), or 0 if the ranges are equal
+            Ok(state.data[a..a + len]
+                .iter()
+                .zip(&state.data[b..b + len])
+                .find(|(x, y)| x != y)
+                .map(|(&x, &y)| u32::from(x).wrapping_sub(u32::from(y)))
+                .unwrap_or(0))
+        }),
+
         intrinsic!(exit, [0], _ => {
             std::process::exit(0)
         }),
@@ -126,7 +167,17 @@ lazy_static! {
         }),
 
         intrinsic!(present, [0], (_, state) => {
-            state.graphics.present();
+            if let Some(framebuffer) = &mut state.framebuffer {
+                framebuffer.update(&state.data)?;
+            }
+            let memory = &state.data[..];
+            let palette = state
+                .palette_screen
+                .as_mut()
+                .map(|palette_screen| (palette_screen, memory));
+            state
+                .graphics
+                .present(&state.targets, palette, state.framebuffer.as_ref())?;
             Ok(0)
         }),
 
@@ -134,18 +185,115 @@ lazy_static! {
             state.graphics.draw_color(args[0]);
             Ok(0)
         }),
+        intrinsic!(setpalette, [2], (args, state) => {
+            let address = palette_address(state, args[0])?;
+            state.set_memory_u32(address, args[1])?;
+            Ok(0)
+        }),
+        intrinsic!(cyclepalette, [2], (args, state) => {
+            let first = palette_address(state, args[0])?;
+            let last = palette_address(state, args[1])?;
+            // each color moves one index towards last, and the color at last wraps around to first
+            if first <= last {
+                state.data[first..last + 4].rotate_right(4);
+            } else {
+                state.data[last..first + 4].rotate_left(4);
+            }
+            Ok(0)
+        }),
         intrinsic!(pixel, [2], (args, state) => {
-            state.graphics.pixel(args[0], args[1])?;
+            let a = signed(args);
+            state.graphics.pixel(a[0], a[1])?;
             Ok(0)
         }),
         intrinsic!(fillrect, [4], (args, state) => {
-            state.graphics.fill_rect(args[0], args[1], args[2], args[3])?;
+            let a = signed(args);
+            state.graphics.fill_rect(a[0], a[1], a[2], a[3])?;
             Ok(0)
         }),
         intrinsic!(line, [4], (args, state) => {
-            state.graphics.line(args[0], args[1], args[2], args[3])?;
+            let a = signed(args);
+            state.graphics.line(a[0], a[1], a[2], a[3])?;
             Ok(0)
         }),
+        intrinsic!(rect, [4], (args, state) => {
+            let a = signed(args);
+            state.graphics.rect(a[0], a[1], a[2], a[3])?;
+            Ok(0)
+        }),
+        intrinsic!(circle, [3], (args, state) => {
+            let a = signed(args);
+            state.graphics.ellipse(a[0], a[1], a[2], a[2])?;
+            Ok(0)
+        }),
+        intrinsic!(fillcircle, [3], (args, state) => {
+            let a = signed(args);
+            state.graphics.fill_ellipse(a[0], a[1], a[2], a[2])?;
+            Ok(0)
+        }),
+        intrinsic!(ellipse, [4], (args, state) => {
+            let a = signed(args);
+            state.graphics.ellipse(a[0], a[1], a[2], a[3])?;
+            Ok(0)
+        }),
+        intrinsic!(triangle, [6], (args, state) => {
+            let a = signed(args);
+            state.graphics.triangle([(a[0], a[1]), (a[2], a[3]), (a[4], a[5])])?;
+            Ok(0)
+        }),
+        intrinsic!(filltriangle, [6], (args, state) => {
+            let a = signed(args);
+            state.graphics.fill_triangle([(a[0], a[1]), (a[2], a[3]), (a[4], a[5])])?;
+            Ok(0)
+        }),
+        intrinsic!(floodfill, [2], (args, state) => {
+            let a = signed(args);
+            state.graphics.flood_fill(a[0], a[1])?;
+            Ok(0)
+        }),
+        intrinsic!(cliprect, [0, 4], (args, state) => {
+            let a = signed(args);
+            state.graphics.set_clip_rect(match a[..] {
+                [x, y, w, h] => Some((x, y, w, h)),
+                _ => None,
+            });
+            Ok(0)
+        }),
+        intrinsic!(drawtext, [3], (args, state) => {
+            let mut text = Vec::new();
+            let mut i = args[0] as usize;
+            while state.get_memory_u8(i)? != 0 {
+                text.push(state.get_memory_u8(i)?);
+                i += 1;
+            }
+            let a = signed(args);
+            state.graphics.text(&text, a[1], a[2])?;
+            Ok(0)
+        }),
+        intrinsic!(drawnum, [3], (args, state) => {
+            let a = signed(args);
+            state
+                .graphics
+                .text(args[0].to_string().as_bytes(), a[1], a[2])?;
+            Ok(0)
+        }),
+        intrinsic!(screen, [3], (args, state) => {
+            if state.framebuffer.is_some() {
+                return Err(Error::Graphics(String::from(
+                    "the screen size can not change while using a framebuffer",
+                )));
+            }
+            state
+                .graphics
+                .set_screen_size(&mut state.targets, args[0], args[1], args[2])?;
+            Ok(0)
+        }),
+        intrinsic!(screenwidth, [0], (_, state) => {
+            Ok(state.graphics.width())
+        }),
+        intrinsic!(screenheight, [0], (_, state) => {
+            Ok(state.graphics.height())
+        }),
         intrinsic!(keypressed, [1], (args, state) => {
             Ok(if state.graphics.is_key_pressed(args[0])? {
                 1
@@ -185,15 +333,110 @@ lazy_static! {
             let sprite_data = unsafe { &state.data.get_unchecked(data_start_index..data_end_index) };
             Ok(state.sprites.create_sprite_mono(sprite_data, w, h, color)?)
         }),
+        intrinsic!(createsprite, [3], (args, state) => {
+            let (start, w, h) = (args[0] as usize, args[1], args[2]);
+            // 4 bytes per pixel
+            let len = w as usize * h as usize * 4;
+            state.check_memory_range(start, len)?;
+            state.sprites.create_sprite(&state.data[start..start + len], w, h)
+        }),
+        intrinsic!(createpalettesprite, [4, 5], (args, state) => {
+            let (start, w, h, palette_start) = (args[0] as usize, args[1], args[2], args[3] as usize);
+            let bits_per_pixel = args.get(4).copied().unwrap_or(8);
+            check_sprite_size(w, h)?;
+            let len = palette_row_len(w, bits_per_pixel)? * h as usize;
+            state.check_memory_range(start, len)?;
+            // one RGBA8888 color for every possible pixel value, whether or not the pixels use it.
+            // This reads 1 << bits_per_pixel colors (1 KB at 8 bits per pixel)
+            let palette_len = 1 << bits_per_pixel;
+            let palette = (0..palette_len)
+                .map(|i| state.get_memory_u32(palette_start + i * 4))
+                .collect::<IResult<Vec<u32>>>()?;
+            state.sprites.create_sprite_palette(
+                &state.data[start..start + len],
+                w,
+                h,
+                palette,
+                bits_per_pixel,
+            )
+        }),
+        intrinsic!(updatesprite, [2], (args, state) => {
+            let start = args[1] as usize;
+            let len = state.sprites.data_len(args[0])?;
+            state.check_memory_range(start, len)?;
+            state.sprites.update_sprite(args[0], &state.data[start..start + len])?;
+            Ok(0)
+        }),
+        intrinsic!(freesprite, [1], (args, state) => {
+            state.sprites.free_sprite(args[0])?;
+            Ok(0)
+        }),
         intrinsic!(sprite, [3], (args, state) => {
+            let a = signed(args);
+            state.graphics.sprite(&state.sprites, args[0], a[1], a[2])?;
+            Ok(0)
+        }),
+        intrinsic!(spriteex, [4, 8], (args, state) => {
+            let source = match args {
+                [_, _, _, _, x, y, w, h] => Some((*x, *y, *w, *h)),
+                _ => None,
+            };
+            let a = signed(args);
+            let (x, y) = (a[1], a[2]);
+            state
+                .graphics
+                .sprite_ex(&state.sprites, args[0], x, y, args[3], source)?;
+            Ok(0)
+        }),
+        intrinsic!(createtarget, [2], (args, state) => {
+            state.graphics.create_target(&mut state.targets, args[0], args[1])
+        }),
+        intrinsic!(settarget, [1], (args, state) => {
+            state.graphics.set_target(&state.targets, args[0])?;
+            Ok(0)
+        }),
+        intrinsic!(drawtarget, [3], (args, state) => {
+            let a = signed(args);
+            state.graphics.draw_target(&state.targets, args[0], a[1], a[2])?;
+            Ok(0)
+        }),
+        intrinsic!(setlayer, [2, 4], (args, state) => {
+            let a = signed(args);
+            let (x, y) = match a[..] {
+                [_, _, x, y] => (x, y),
+                _ => (0, 0),
+            };
             state
                 .graphics
-                .sprite(&state.sprites, args[0], args[1], args[2])?;
+                .set_layer(&mut state.targets, args[0], args[1], x, y)?;
             Ok(0)
         })
     ];
 }
 
+/// Drawing intrinsics take signed coordinates and sizes, so eg. 0 - 1 is -1
+fn signed(args: &[u32]) -> Vec<i32> {
+    args.iter().map(|&arg| arg as i32).collect()
+}
+
+/// Returns the address of a color in the palette, or an error if there is no palette
+fn palette_address(state: &InterpreterState, index: u32) -> IResult<usize> {
+    let start = state.constants["PALETTE_START"] as usize;
+    let end = state.constants["PALETTE_END"] as usize;
+    if start == end {
+        return Err(Error::Graphics(String::from(
+            "there is no palette without --palette or --framebuffer indexed",
+        )));
+    }
+    if index as usize >= PALETTE_COLORS {
+        return Err(Error::Graphics(format!(
+            "palette index {} is out of bounds, the palette has {} colors",
+            index, PALETTE_COLORS
+        )));
+    }
+    Ok(start + index as usize * 4)
+}
+
 fn flush_stdout() -> IResult<()> {
     stdout()
         .flush()
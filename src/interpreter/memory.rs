@@ -0,0 +1,240 @@
+//! Layout of the interpreter's memory segment.
+//!
+//! Memory is split into regions, in order: data, variables, palette, framebuffer, heap, stack.
+//! When no memory size is given, memory only holds the data, variables, palette and framebuffer,
+//! and the heap and stack are empty.
+//! The palette is empty unless palette mode or an indexed framebuffer is used,
+//! and the framebuffer is empty unless a framebuffer is used.
+
+use crate::error::{Error, IResult};
+
+/// Names of the built in constants describing the layout, in the order of MemoryLayout::constants
+pub const CONSTANT_NAMES: &[&str] = &[
+    "MEMORY_SIZE",
+    "DATA_START",
+    "DATA_END",
+    "VARS_START",
+    "VARS_END",
+    "PALETTE_START",
+    "PALETTE_END",
+    "FRAMEBUFFER_START",
+    "FRAMEBUFFER_END",
+    "HEAP_START",
+    "HEAP_END",
+    "STACK_START",
+    "STACK_END",
+];
+
+/// Largest number of bytes of memory, so that every address and every constant fits in a u32
+pub const MAX_MEMORY_SIZE: usize = u32::MAX as usize;
+
+/// User settings for the memory layout
+pub struct MemoryConfig {
+    /// Total size of memory in bytes. None means memory is only as big as the data and variables.
+    pub size: Option<usize>,
+    pub stack_size: usize,
+    /// Size of the palette region in bytes
+    pub palette_size: usize,
+    /// Size of the framebuffer region in bytes
+    pub framebuffer_size: usize,
+}
+
+/// The byte boundaries of each memory region. The data region always starts at 0,
+/// and each region starts where the previous one ends.
+pub struct MemoryLayout {
+    pub data_end: usize,
+    pub variables_end: usize,
+    pub palette_end: usize,
+    pub framebuffer_end: usize,
+    pub heap_end: usize,
+    pub stack_end: usize,
+}
+
+impl MemoryLayout {
+    /// Lay out memory for a data segment of the given length and the given number of u32 variables
+    pub fn try_new(data_len: usize, variable_count: usize, config: &MemoryConfig) -> IResult<Self> {
+        let variables_end = region_end(data_len, variable_count.saturating_mul(4))?;
+        let palette_end = region_end(variables_end, config.palette_size)?;
+        let framebuffer_end = region_end(palette_end, config.framebuffer_size)?;
+        match config.size {
+            None => Ok(MemoryLayout {
+                data_end: data_len,
+                variables_end,
+                palette_end,
+                framebuffer_end,
+                heap_end: framebuffer_end,
+                stack_end: framebuffer_end,
+            }),
+            Some(size) => {
+                if size > MAX_MEMORY_SIZE {
+                    return Err(Error::MemoryTooLarge);
+                }
+                let required = region_end(framebuffer_end, config.stack_size)?;
+                if required > size {
+                    return Err(Error::MemoryTooSmall {
+                        required,
+                        available: size,
+                    });
+                }
+                Ok(MemoryLayout {
+                    data_end: data_len,
+                    variables_end,
+                    palette_end,
+                    framebuffer_end,
+                    heap_end: size - config.stack_size,
+                    stack_end: size,
+                })
+            }
+        }
+    }
+
+    /// Total number of bytes of memory
+    pub fn size(&self) -> usize {
+        self.stack_end
+    }
+
+    /// Names and values of the built in constants describing the layout.
+    pub fn constants(&self) -> Vec<(&'static str, u32)> {
+        let values = [
+            self.stack_end,
+            0,
+            self.data_end,
+            self.data_end,
+            self.variables_end,
+            self.variables_end,
+            self.palette_end,
+            self.palette_end,
+            self.framebuffer_end,
+            self.framebuffer_end,
+            self.heap_end,
+            self.heap_end,
+            self.stack_end,
+        ];
+        CONSTANT_NAMES
+            .iter()
+            .zip(values.iter())
+            .map(|(&name, &value)| (name, value as u32))
+            .collect()
+    }
+}
+
+/// Returns the end of a region of `len` bytes starting at `start`,
+/// or an error if it is past the end of the largest possible memory
+fn region_end(start: usize, len: usize) -> IResult<usize> {
+    start
+        .checked_add(len)
+        .filter(|&end| end <= MAX_MEMORY_SIZE)
+        .ok_or(Error::MemoryTooLarge)
+}
+
+/// Parses a size in bytes, with an optional `K` or `M` suffix (eg. `64K`)
+pub fn parse_size(s: &str) -> Result<usize, String> {
+    let (digits, multiplier) = match s.chars().last() {
+        Some('K') | Some('k') => (&s[..s.len() - 1], 1024),
+        Some('M') | Some('m') => (&s[..s.len() - 1], 1024 * 1024),
+        _ => (s, 1),
+    };
+    let n = digits
+        .parse::<usize>()
+        .map_err(|_| format!("invalid size `{}`", s))?;
+    n.checked_mul(multiplier)
+        .filter(|&size| size <= MAX_MEMORY_SIZE)
+        .ok_or_else(|| format!("size `{}` is too large", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(size: Option<usize>, stack_size: usize) -> MemoryConfig {
+        MemoryConfig {
+            size,
+            stack_size,
+            palette_size: 0,
+            framebuffer_size: 0,
+        }
+    }
+
+    #[test]
+    fn sizes_past_u32_are_rejected() {
+        assert_eq!(parse_size("4194303K"), Ok(4194303 * 1024));
+        assert!(parse_size("4194304K").is_err());
+        assert!(parse_size(&usize::MAX.to_string()).is_err());
+    }
+
+    #[test]
+    fn regions_are_in_order() {
+        let config = MemoryConfig {
+            size: Some(16384),
+            stack_size: 1024,
+            palette_size: 1024,
+            framebuffer_size: 96 * 64,
+        };
+        let layout = MemoryLayout::try_new(10, 3, &config).unwrap();
+        assert_eq!(layout.data_end, 10);
+        assert_eq!(layout.variables_end, 22);
+        assert_eq!(layout.palette_end, 1046);
+        assert_eq!(layout.framebuffer_end, 1046 + 96 * 64);
+        assert_eq!(layout.heap_end, 15360);
+        assert_eq!(layout.stack_end, 16384);
+        assert_eq!(layout.size(), 16384);
+        let constants = layout.constants();
+        assert_eq!(constants[0], ("MEMORY_SIZE", 16384));
+        assert_eq!(constants[7], ("FRAMEBUFFER_START", 1046));
+        assert_eq!(constants[9], ("HEAP_START", 1046 + 96 * 64));
+    }
+
+    #[test]
+    fn memory_without_a_size_ends_after_the_framebuffer() {
+        let config = MemoryConfig {
+            size: None,
+            stack_size: 1024,
+            palette_size: 1024,
+            framebuffer_size: 4,
+        };
+        let layout = MemoryLayout::try_new(8, 0, &config).unwrap();
+        assert_eq!(layout.palette_end, 1032);
+        assert_eq!(layout.framebuffer_end, 1036);
+        assert_eq!(layout.heap_end, 1036);
+        assert_eq!(layout.stack_end, 1036);
+    }
+
+    #[test]
+    fn memory_must_fit_the_framebuffer_and_stack() {
+        let config = MemoryConfig {
+            size: Some(2048),
+            stack_size: 1024,
+            palette_size: 0,
+            framebuffer_size: 1024,
+        };
+        assert!(MemoryLayout::try_new(0, 0, &config).is_ok());
+        assert!(matches!(
+            MemoryLayout::try_new(0, 1, &config),
+            Err(Error::MemoryTooSmall {
+                required: 2052,
+                available: 2048
+            })
+        ));
+    }
+
+    #[test]
+    fn layouts_past_u32_are_rejected() {
+        assert!(MemoryLayout::try_new(0, 0, &config(Some(MAX_MEMORY_SIZE), 4)).is_ok());
+        assert!(matches!(
+            MemoryLayout::try_new(0, 0, &config(Some(MAX_MEMORY_SIZE + 1), 4)),
+            Err(Error::MemoryTooLarge)
+        ));
+        assert!(matches!(
+            MemoryLayout::try_new(MAX_MEMORY_SIZE, 1, &config(None, 0)),
+            Err(Error::MemoryTooLarge)
+        ));
+        assert!(matches!(
+            MemoryLayout::try_new(16, 0, &config(Some(1024), usize::MAX)),
+            Err(Error::MemoryTooLarge)
+        ));
+        assert!(matches!(
+            MemoryLayout::try_new(16, usize::MAX, &config(None, 0)),
+            Err(Error::MemoryTooLarge)
+        ));
+    }
+}
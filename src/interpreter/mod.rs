@@ -1,15 +1,19 @@
 //! The interpreter that runs IntermediateLine IR
 
+use self::memory::{MemoryConfig, MemoryLayout};
 use self::state::InterpreterState;
-use crate::error::{Error, IResult};
-use crate::graphics::{Graphics, Sprites};
+use crate::error::{Error, IResult, Warning};
+use crate::graphics::{Framebuffer, Graphics, GraphicsConfig, PaletteScreen, Sprites, Targets};
 use crate::intermediate_repr::{IntermediateBlock, IntermediateBlockSlice, IntermediateLine, DataSegment};
+use sdl2::pixels::Color;
 
 use std::borrow::Cow;
 use std::collections::HashMap;
 
 mod intrinsics;
+pub mod memory;
 mod state;
+mod variables;
 
 /// The immutable program data that is run by the interpreter
 pub struct Program<'a> {
@@ -17,35 +21,86 @@ pub struct Program<'a> {
     data: Vec<u8>,
     /// Maps labels to the line that they point to in code
     label_table: HashMap<Cow<'a, str>, usize>,
-    /// Maps data labels to the byte in data they point to
-    data_label_table: HashMap<&'a str, usize>,
+    /// Maps data labels and variables to the byte in memory they point to
+    var_table: HashMap<&'a str, usize>,
+    layout: MemoryLayout,
+    pub warnings: Vec<Warning>,
 }
 
 impl<'a> Program<'a> {
-    pub fn try_new(ir: IntermediateBlock<'a>, data_segment: DataSegment<'a>) -> IResult<Self> {
+    pub fn try_new(
+        ir: IntermediateBlock<'a>,
+        data_segment: DataSegment<'a>,
+        memory_config: &MemoryConfig,
+        implicit_vars: bool,
+    ) -> IResult<Self> {
         let label_table = build_label_table(&ir)?;
+        let DataSegment(data, mut var_table) = data_segment;
+        let (var_names, mut warnings) =
+            variables::resolve_variables(&ir, &var_table, implicit_vars)?;
+        warnings.extend(find_shadowed_names(&ir, &var_table, &var_names));
+        let layout = MemoryLayout::try_new(data.len(), var_names.len(), memory_config)?;
+
+        // variables are allocated one after another, straight after the data
+        for (i, name) in var_names.into_iter().enumerate() {
+            var_table.insert(name, layout.data_end + i * 4);
+        }
+
         Ok(Program {
             ir,
-            data: data_segment.0,
+            data,
             label_table,
-            data_label_table: data_segment.1
+            var_table,
+            layout,
+            warnings,
         })
     }
 }
 
-pub fn execute<'a>(program: &Program<'a>) -> IResult<()> {
+pub fn execute<'a>(program: &Program<'a>, graphics_config: &GraphicsConfig) -> IResult<()> {
     // TODO take ownership of program so clones are not needed?
-    let graphics = Graphics::try_new()?;
+    let mut graphics = Graphics::try_new(graphics_config)?;
     let sprite_creator = &graphics.get_sprite_creator();
     let sprites = Sprites::new(&sprite_creator);
+    let targets = Targets::try_new(sprite_creator, graphics_config.width, graphics_config.height)?;
+    graphics.set_target(&targets, 0)?;
+    graphics.clear_with(Color::RGBA(0, 0, 0, 0));
+    let framebuffer = match graphics_config.framebuffer {
+        Some(format) => Some(Framebuffer::try_new(
+            sprite_creator,
+            format,
+            graphics_config.width,
+            graphics_config.height,
+            program.layout.variables_end,
+            program.layout.palette_end,
+        )?),
+        None => None,
+    };
+    let palette_screen = if graphics_config.palette_mode {
+        Some(PaletteScreen::try_new(
+            sprite_creator,
+            graphics_config.width,
+            graphics_config.height,
+            program.layout.variables_end,
+        )?)
+    } else {
+        None
+    };
+
+    // copy user defined data into the start of a mutable memory vec
+    let mut data = vec![0; program.layout.size()];
+    data[..program.data.len()].copy_from_slice(&program.data);
 
     let mut state = InterpreterState {
-        // clone user defined data into a mutable vec
-        data: program.data.clone(),
-        var_table: program.data_label_table.clone(),
+        data,
+        var_table: program.var_table.clone(),
+        constants: program.layout.constants().into_iter().collect(),
         instr_index: 0,
         graphics,
         sprites,
+        targets,
+        framebuffer,
+        palette_screen,
     };
 
     while state.instr_index < program.ir.len() {
@@ -72,3 +127,34 @@ pub fn build_label_table<'a>(
     }
     Ok(map)
 }
+
+/// Warns about code labels and functions that have the same name as a data label or variable.
+/// This is allowed (code names and data names are separate), but is confusing.
+fn find_shadowed_names(
+    program: &IntermediateBlockSlice<'_>,
+    data_labels: &HashMap<&str, usize>,
+    var_names: &[&str],
+) -> Vec<Warning> {
+    program
+        .iter()
+        .filter_map(|line| {
+            let (name, code_kind) = match line {
+                IntermediateLine::Label(name) => (name, "code label"),
+                IntermediateLine::FunDeclaration(name, ..) => (name, "function"),
+                _ => return None,
+            };
+            let data_kind = if data_labels.contains_key(name.as_ref()) {
+                "data label"
+            } else if var_names.contains(&name.as_ref()) {
+                "variable"
+            } else {
+                return None;
+            };
+            Some(Warning::NameShadowed {
+                name: name.to_string(),
+                data_kind,
+                code_kind,
+            })
+        })
+        .collect()
+}
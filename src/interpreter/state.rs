@@ -4,7 +4,7 @@ use crate::error::Error;
 use super::intrinsics::try_execute_intrinsic;
 use super::{IResult, Program};
 use crate::ast::{AssignTarget, Expr};
-use crate::graphics::{Graphics, Sprites};
+use crate::graphics::{Framebuffer, Graphics, PaletteScreen, Sprites, Targets};
 use crate::intermediate_repr::IntermediateLine;
 
 use std::collections::HashMap;
@@ -13,13 +13,21 @@ use std::collections::HashMap;
 pub struct InterpreterState<'a> {
     /// The interpreter's memory segment (contains variables and user defined data)
     pub data: Vec<u8>,
-    /// Maps vars (as they are created) to their location (byte index) in the data vec
+    /// Maps vars (allocated when the program is loaded) to their location (byte index) in the data vec
     pub var_table: HashMap<&'a str, usize>,
+    /// Built in read-only names (eg. memory region bounds)
+    pub constants: HashMap<&'static str, u32>,
     /// current instruction pointer
     pub instr_index: usize,
 
     pub graphics: Graphics,
     pub sprites: Sprites<'a>,
+    /// The screen and off-screen targets
+    pub targets: Targets<'a>,
+    /// Drawn from memory on present, if the program uses a framebuffer
+    pub framebuffer: Option<Framebuffer<'a>>,
+    /// Converts the screen's palette indices to colors on present, in palette mode
+    pub palette_screen: Option<PaletteScreen<'a>>,
 }
 
 impl<'a> InterpreterState<'a> {
@@ -49,6 +57,12 @@ impl<'a> InterpreterState<'a> {
                         // and store it into a single byte of the data vec
                         self.set_memory_u8(store_address as usize, value_to_assign as u8)?;
                     }
+                    AssignTarget::Field(..) => {
+                        unreachable!("field assignments are lowered by structs::lower_field_access")
+                    }
+                    AssignTarget::Index(..) => {
+                        unreachable!("array assignments are lowered by arrays::lower_indexing")
+                    }
                 }
             }
 
@@ -65,9 +79,10 @@ impl<'a> InterpreterState<'a> {
                 }
             }
 
-            // Ignore labels, function decls, and function returns
+            // Ignore labels, function decls, function returns and declarations
             // Note, this means that execution can fall through into functions
-            Label(..) | FunDeclaration(..) | FunReturn => {}
+            Label(..) | FunDeclaration(..) | FunReturn | VarDeclaration(..) | Const(..)
+            | Struct(..) => {}
             Goto(name) => {
                 self.instr_index = *program
                     .label_table
@@ -88,14 +103,14 @@ impl<'a> InterpreterState<'a> {
             Literal(n) => Ok(*n),
             Add(l, r) => self.bin_op(&l, &r, program, |a, b| a.wrapping_add(b)),
             Sub(l, r) => self.bin_op(&l, &r, program, |a, b| a.wrapping_sub(b)),
-            Mul(l, r) => self.bin_op(&l, &r, program, |a, b| a * b),
+            Mul(l, r) => self.bin_op(&l, &r, program, u32::wrapping_mul),
             Div(l, r) => self.bin_op(&l, &r, program, |a, b| a / b),
             Mod(l, r) => self.bin_op(&l, &r, program, |a, b| a % b),
             BitAnd(l, r) => self.bin_op(&l, &r, program, |a, b| a & b),
             BitOr(l, r) => self.bin_op(&l, &r, program, |a, b| a | b),
             BitXor(l, r) => self.bin_op(&l, &r, program, |a, b| a ^ b),
-            Shl(l, r) => self.bin_op(&l, &r, program, |a, b| a << b),
-            Shr(l, r) => self.bin_op(&l, &r, program, |a, b| a >> b),
+            Shl(l, r) => self.bin_op(&l, &r, program, u32::wrapping_shl),
+            Shr(l, r) => self.bin_op(&l, &r, program, u32::wrapping_shr),
             Lt(l, r) => self.bin_bool_op(&l, &r, program, |a, b| a < b),
             Gt(l, r) => self.bin_bool_op(&l, &r, program, |a, b| a > b),
             Leq(l, r) => self.bin_bool_op(&l, &r, program, |a, b| a <= b),
@@ -128,6 +143,20 @@ impl<'a> InterpreterState<'a> {
                 self.get_memory_u8(addr).map(u32::from)
             }
             VarAddress(name) => Ok(self.get_var_address(name) as u32),
+            Field(..) => unreachable!("field accesses are lowered by structs::lower_field_access"),
+            Index(..) => unreachable!("array accesses are lowered by arrays::lower_indexing"),
+            BoundsCheck(index, length, array) => {
+                let index = self.evaluate_expr(index, program)?;
+                if index < *length {
+                    Ok(index)
+                } else {
+                    Err(Error::IndexOutOfBounds {
+                        array: array.to_string(),
+                        index,
+                        length: *length,
+                    })
+                }
+            }
         }
     }
 
@@ -235,7 +264,7 @@ impl<'a> InterpreterState<'a> {
     }
 
     /// Access a u32 at the specified *byte* index. Error on out of bounds.
-    fn get_memory_u32(&self, index: usize) -> IResult<u32> {
+    pub fn get_memory_u32(&self, index: usize) -> IResult<u32> {
         if index + 3 >= self.data.len() {
             Err(Error::U32OutOfBounds {
                 u32_read_index: index,
@@ -251,8 +280,8 @@ impl<'a> InterpreterState<'a> {
         }
     }
 
-    /// Set the 4 bytes at the specified *byte* index to the value, big endian. Error on out of bounds.
-    fn set_memory_u32(&mut self, index: usize, value: u32) -> IResult<()> {
+    /// Set the 4 bytes at the specified *byte* index to the value, little endian. Error on out of bounds.
+    pub fn set_memory_u32(&mut self, index: usize, value: u32) -> IResult<()> {
         if index + 3 >= self.data.len() {
             Err(Error::U32OutOfBounds {
                 u32_read_index: index,
@@ -289,37 +318,37 @@ impl<'a> InterpreterState<'a> {
         }
     }
 
-    /// Returns value of var based on data vec and var table.
-    /// As with get_var_addres, it allocates space for a variable and
-    /// returns the alloc'd space if the var doesn't exist
-    fn get_var_value(&mut self, name: &'a str) -> IResult<u32> {
-        let addr = self.get_var_address(name);
-        self.get_memory_u32(addr)
+    /// Check that the `len` bytes starting at index are all inside memory. Error on out of bounds.
+    /// A zero length range is valid if index is at most the length of memory, so it can be sliced.
+    pub fn check_memory_range(&self, index: usize, len: usize) -> IResult<()> {
+        if index + len > self.data.len() {
+            Err(Error::U8OutOfBounds {
+                u8_read_index: index + len.saturating_sub(1),
+                memory_length: self.data.len(),
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns value of var (or built in constant) based on data vec and var table.
+    fn get_var_value(&self, name: &'a str) -> IResult<u32> {
+        if let Some(&value) = self.constants.get(name) {
+            return Ok(value);
+        }
+        self.get_memory_u32(self.get_var_address(name))
     }
 
-    /// Sets the var of name to the specified value. As with get_var_address,
-    /// it allocates space for the var if the name does not already exist
+    /// Sets the var of name to the specified value.
     fn set_var_value(&mut self, name: &'a str, value: u32) -> IResult<()> {
-        let addr = self.get_var_address(name);
-        self.set_memory_u32(addr, value)?;
-        Ok(())
+        self.set_memory_u32(self.get_var_address(name), value)
     }
 
     /// Returns the address in memory (data vec) that a var points to.
-    /// If the var does not already exist, append a slot to memory and point the var's name to the new slot
-    fn get_var_address(&mut self, name: &'a str) -> usize {
-        if self.var_table.contains_key(name) {
-            self.var_table[name]
-        } else {
-            let next_addr_in_data = self.data.len();
-            self.var_table.insert(name, next_addr_in_data);
-            // push 4 bytes (to fit a u32 variable)
-            self.data.push(0);
-            self.data.push(0);
-            self.data.push(0);
-            self.data.push(0);
-            next_addr_in_data
-        }
+    fn get_var_address(&self, name: &str) -> usize {
+        // Do not need to error check the hashmap get because every var
+        // used in the program is allocated when the program is loaded
+        self.var_table[name]
     }
 
     /// Look up all the vars specivied in the params slice and return a map of their name->value (vec of tuples)
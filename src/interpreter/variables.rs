@@ -0,0 +1,161 @@
+//! Finds every variable used by a program, so they can all be allocated before it runs.
+//! This gives each variable a stable address, no matter which code path uses it first.
+
+use super::memory::CONSTANT_NAMES;
+use crate::ast::{AssignTarget, Expr};
+use crate::error::{Error, IResult, Warning};
+use crate::intermediate_repr::{IntermediateBlockSlice, IntermediateLine};
+
+use std::collections::{HashMap, HashSet};
+
+/// Name of the variable that functions return through. It always exists.
+const RETURN_VAR: &str = "ans";
+
+/// Returns the names of all variables used by the program (in order of appearance)
+/// and warnings for suspicious variable use.
+/// If implicit_vars is false, every variable must be declared by `var`, be a data label or be a function parameter.
+pub fn resolve_variables<'a>(
+    ir: &IntermediateBlockSlice<'a>,
+    data_labels: &HashMap<&'a str, usize>,
+    implicit_vars: bool,
+) -> IResult<(Vec<&'a str>, Vec<Warning>)> {
+    let mut resolver = Resolver {
+        data_labels,
+        implicit_vars,
+        declared: HashSet::new(),
+        names: Vec::new(),
+        allocated: HashSet::new(),
+        written: HashSet::new(),
+        read: Vec::new(),
+    };
+
+    // declarations may appear after a variable's first use, so find them all first
+    resolver.declared.insert(RETURN_VAR);
+    for line in ir {
+        match line {
+            IntermediateLine::VarDeclaration(names) | IntermediateLine::FunDeclaration(_, names, _) => {
+                resolver.declared.extend(names)
+            }
+            _ => {}
+        }
+    }
+
+    resolver.write(RETURN_VAR)?;
+    for line in ir {
+        resolver.resolve_line(line)?;
+    }
+
+    let warnings = resolver
+        .read
+        .iter()
+        .filter(|name| !resolver.written.contains(*name))
+        .map(|name| Warning::VarNeverWritten(name.to_string()))
+        .collect();
+    Ok((resolver.names, warnings))
+}
+
+struct Resolver<'a, 'b> {
+    data_labels: &'b HashMap<&'a str, usize>,
+    implicit_vars: bool,
+    declared: HashSet<&'a str>,
+    /// Variables to allocate, in order of first appearance
+    names: Vec<&'a str>,
+    allocated: HashSet<&'a str>,
+    /// Variables that are assigned to or have their address taken
+    written: HashSet<&'a str>,
+    /// Variables that are read, in order of first read
+    read: Vec<&'a str>,
+}
+
+impl<'a, 'b> Resolver<'a, 'b> {
+    fn resolve_line(&mut self, line: &IntermediateLine<'a>) -> IResult<()> {
+        match line {
+            IntermediateLine::Assign(target, expr) => {
+                self.resolve_expr(expr)?;
+                match target {
+                    AssignTarget::Var(name) => self.write(name)?,
+                    // only the memory the variable points to is written
+                    AssignTarget::Field(name, _) => self.read(name)?,
+                    AssignTarget::Index(_, index) => self.resolve_expr(index)?,
+                    AssignTarget::Addr(addr) | AssignTarget::ByteAddr(addr) => {
+                        self.resolve_expr(addr)?
+                    }
+                }
+            }
+            IntermediateLine::JumpFalse(expr, _) | IntermediateLine::Expr(expr) => {
+                self.resolve_expr(expr)?
+            }
+            IntermediateLine::FunDeclaration(_, params, _) => {
+                for param in params {
+                    self.write(param)?;
+                }
+            }
+            IntermediateLine::VarDeclaration(names) => {
+                for name in names {
+                    self.allocate(name)?;
+                }
+            }
+            IntermediateLine::Goto(_)
+            | IntermediateLine::Label(_)
+            | IntermediateLine::FunReturn
+            | IntermediateLine::Const(..)
+            | IntermediateLine::Struct(..) => {}
+        }
+        Ok(())
+    }
+
+    fn resolve_expr(&mut self, expr: &Expr<'a>) -> IResult<()> {
+        let mut result = Ok(());
+        expr.visit(&mut |e| {
+            if result.is_err() {
+                return;
+            }
+            result = match e {
+                Expr::Var(name) | Expr::Field(name, _) => self.read(name),
+                // the var could be written through the address, so count it as written
+                Expr::VarAddress(name) => {
+                    if CONSTANT_NAMES.contains(name) {
+                        Err(Error::ConstantHasNoAddress(name.to_string()))
+                    } else {
+                        self.write(name)
+                    }
+                }
+                _ => Ok(()),
+            }
+        });
+        result
+    }
+
+    fn read(&mut self, name: &'a str) -> IResult<()> {
+        if CONSTANT_NAMES.contains(&name) {
+            return Ok(());
+        }
+        if !self.read.contains(&name) {
+            self.read.push(name);
+        }
+        self.allocate(name)
+    }
+
+    fn write(&mut self, name: &'a str) -> IResult<()> {
+        if CONSTANT_NAMES.contains(&name) {
+            return Err(Error::AssignToConstant(name.to_string()));
+        }
+        self.written.insert(name);
+        self.allocate(name)
+    }
+
+    /// Add name to the variables to allocate, unless it is a data label (which already has an address)
+    fn allocate(&mut self, name: &'a str) -> IResult<()> {
+        if self.data_labels.contains_key(name) {
+            self.written.insert(name);
+            return Ok(());
+        }
+        if !self.implicit_vars && !self.declared.contains(name) {
+            return Err(Error::UndeclaredVariable(name.to_string()));
+        }
+        if self.allocated.insert(name) {
+            self.names.push(name);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,112 @@
+//! Loads a program file along with every file it includes and every library it imports
+
+use crate::ast::Section;
+use crate::error::{Error, IResult};
+use crate::grammar;
+use crate::stdlib::library_source;
+
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+
+/// The text of a loaded file or library
+pub struct SourceFile {
+    /// For libraries, this is `std:NAME`
+    pub path: PathBuf,
+    pub text: String,
+}
+
+/// A file or library used by another file
+enum Dependency {
+    File(PathBuf),
+    Library(String),
+}
+
+impl SourceFile {
+    /// Parses the file's sections, adding the path to any error
+    pub fn parse(&self) -> IResult<Vec<Section<'_>>> {
+        grammar::program(&self.text).map_err(|e| Error::Parse {
+            file: self.path.display().to_string(),
+            message: e.to_string(),
+        })
+    }
+
+    /// The files this file includes (with paths relative to this file) and the libraries it imports
+    fn dependencies(&self) -> IResult<Vec<Dependency>> {
+        let dir = self.path.parent().unwrap_or_else(|| Path::new(""));
+        Ok(self
+            .parse()?
+            .into_iter()
+            .filter_map(|section| match section {
+                Section::Include(path) => Some(Dependency::File(dir.join(path))),
+                Section::Import(name) => Some(Dependency::Library(name.to_string())),
+                _ => None,
+            })
+            .collect())
+    }
+}
+
+/// Reads the file at path and all files it includes and libraries it imports (recursively).
+/// Files are returned in the order their sections should be joined: each file comes before
+/// the files it includes, and included files are in the order they are included in.
+/// Each file is only loaded once, even if it is included several times.
+pub fn load_sources(path: &Path) -> IResult<Vec<SourceFile>> {
+    let mut loader = Loader {
+        include_stack: Vec::new(),
+        sources: Vec::new(),
+    };
+    loader.load(&Dependency::File(path.to_path_buf()))?;
+    Ok(loader.sources)
+}
+
+struct Loader {
+    /// The files currently being loaded, so a file including one of them is a cycle
+    include_stack: Vec<PathBuf>,
+    sources: Vec<SourceFile>,
+}
+
+impl Loader {
+    /// Depth first load of a file or library and its dependencies
+    fn load(&mut self, dependency: &Dependency) -> IResult<()> {
+        let path = match dependency {
+            Dependency::File(path) => path
+                .canonicalize()
+                .map_err(|_| Error::FileNotReadable(path.display().to_string()))?,
+            Dependency::Library(name) => PathBuf::from(format!("std:{}", name)),
+        };
+
+        if self.include_stack.contains(&path) {
+            let mut cycle: Vec<String> = self
+                .include_stack
+                .iter()
+                .skip_while(|p| **p != path)
+                .map(|p| p.display().to_string())
+                .collect();
+            cycle.push(path.display().to_string());
+            return Err(Error::IncludeCycle(cycle));
+        }
+        if self.sources.iter().any(|source| source.path == path) {
+            return Ok(());
+        }
+
+        let text = match dependency {
+            Dependency::File(_) => read_to_string(&path)
+                .map_err(|_| Error::FileNotReadable(path.display().to_string()))?,
+            Dependency::Library(name) => library_source(name)
+                .ok_or_else(|| Error::LibraryNotFound(name.clone()))?
+                .to_string(),
+        };
+        let source = SourceFile {
+            path: path.clone(),
+            text,
+        };
+        let dependencies = source.dependencies()?;
+        self.sources.push(source);
+
+        self.include_stack.push(path);
+        for dependency in &dependencies {
+            self.load(dependency)?;
+        }
+        self.include_stack.pop();
+        Ok(())
+    }
+}
@@ -0,0 +1,235 @@
+//! Macros are blocks of code that are copied in wherever they are invoked, with their parameters
+//! replaced by the invocation's arguments.
+//! Expansion happens during IR conversion (see intermediate_repr), so that labels defined in a
+//! macro can be given a new name for each expansion.
+
+use crate::ast::{AssignTarget, Block, Expr, Line};
+use crate::error::{Error, IResult};
+use std::collections::HashMap;
+
+/// How deep macros can be expanded inside each other, to stop macros that expand themselves
+pub const MAX_EXPANSION_DEPTH: usize = 64;
+
+pub struct Macro<'a> {
+    pub params: Vec<&'a str>,
+    pub body: Block<'a>,
+}
+
+impl<'a> Macro<'a> {
+    /// Returns a copy of the macro body with each parameter replaced by its argument
+    pub fn expand(&self, name: &str, args: &[Expr<'a>]) -> IResult<Block<'a>> {
+        if args.len() != self.params.len() {
+            return Err(Error::MacroArgumentMismatch {
+                expected: self.params.len(),
+                got: args.len(),
+                macro_name: name.to_string(),
+            });
+        }
+        let substitution = Substitution {
+            macro_name: name,
+            args: self.params.iter().cloned().zip(args).collect(),
+        };
+        let mut body = self.body.clone();
+        substitution.block(&mut body)?;
+        Ok(body)
+    }
+}
+
+/// Names of all code labels defined in block (including inside control structures and functions)
+pub fn defined_labels<'a>(block: &Block<'a>) -> Vec<&'a str> {
+    let mut labels = Vec::new();
+    for line in block {
+        match &line.line {
+            Line::Label(name) => labels.push(*name),
+            Line::If(_, then_block, else_block) => {
+                labels.extend(defined_labels(then_block));
+                if let Some(else_block) = else_block {
+                    labels.extend(defined_labels(else_block));
+                }
+            }
+            Line::For(.., body)
+            | Line::While(_, body)
+            | Line::FunDeclaration(_, _, body, _)
+            | Line::Module(_, body) => labels.extend(defined_labels(body)),
+            _ => {}
+        }
+    }
+    labels
+}
+
+/// Replaces the parameters of a macro with their arguments
+struct Substitution<'a, 'b> {
+    macro_name: &'b str,
+    args: HashMap<&'a str, &'b Expr<'a>>,
+}
+
+impl<'a, 'b> Substitution<'a, 'b> {
+    fn block(&self, block: &mut Block<'a>) -> IResult<()> {
+        for line in block {
+            self.line(&mut line.line)?;
+        }
+        Ok(())
+    }
+
+    fn line(&self, line: &mut Line<'a>) -> IResult<()> {
+        match line {
+            Line::Assign(target, e) => {
+                match target {
+                    AssignTarget::Var(name) | AssignTarget::Field(name, _) => self.name(name)?,
+                    AssignTarget::Addr(addr) | AssignTarget::ByteAddr(addr) => self.expr(addr)?,
+                    AssignTarget::Index(name, index) => {
+                        self.name(name)?;
+                        self.expr(index)?
+                    }
+                }
+                self.expr(e)
+            }
+            Line::For(counter_variable, start, end, body) => {
+                self.name(counter_variable)?;
+                self.expr(start)?;
+                self.expr(end)?;
+                self.block(body)
+            }
+            Line::While(condition, body) => {
+                self.expr(condition)?;
+                self.block(body)
+            }
+            Line::If(condition, then_block, else_block) => {
+                self.expr(condition)?;
+                self.block(then_block)?;
+                if let Some(else_block) = else_block {
+                    self.block(else_block)?;
+                }
+                Ok(())
+            }
+            Line::FunDeclaration(_, params, body, _) => {
+                for param in params {
+                    self.name(param)?;
+                }
+                self.block(body)
+            }
+            Line::VarDeclaration(names) => {
+                for name in names {
+                    self.name(name)?;
+                }
+                Ok(())
+            }
+            Line::Module(_, body) => self.block(body),
+            Line::MacroCall(_, args) => {
+                for arg in args {
+                    self.expr(arg)?;
+                }
+                Ok(())
+            }
+            Line::Const(_, e) | Line::Expr(e) => self.expr(e),
+            Line::Goto(_) | Line::Label(_) | Line::Macro(..) | Line::Struct(..) => Ok(()),
+        }
+    }
+
+    /// Replaces a parameter that is used as a variable name (eg. assigned to) with the name of
+    /// the variable passed as its argument
+    fn name(&self, name: &mut &'a str) -> IResult<()> {
+        match self.args.get(name) {
+            Some(Expr::Var(var)) => *name = var,
+            Some(_) => {
+                return Err(Error::MacroArgumentNotVariable {
+                    macro_name: self.macro_name.to_string(),
+                    param: name.to_string(),
+                })
+            }
+            None => {}
+        }
+        Ok(())
+    }
+
+    fn expr(&self, expr: &mut Expr<'a>) -> IResult<()> {
+        expr.try_visit_mut(&mut |e| {
+            match e {
+                Expr::Var(name) => {
+                    if let Some(arg) = self.args.get(name) {
+                        *e = (*arg).clone();
+                    }
+                }
+                Expr::VarAddress(name) | Expr::Field(name, _) | Expr::Index(name, _) => {
+                    self.name(name)?
+                }
+                _ => {}
+            }
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::intermediate_repr::display_intermediate_block;
+    use crate::lower_source;
+
+    /// The IR of a program's code, with macros expanded
+    fn expanded(code: &str) -> String {
+        let source = format!(".DATA\nd:\n0\n.CODE\n{}", code);
+        let (ir, _) = lower_source(&source, false).unwrap();
+        display_intermediate_block(&ir)
+    }
+
+    fn expand_error(code: &str) -> Error {
+        let source = format!(".DATA\nd:\n0\n.CODE\n{}", code);
+        match lower_source(&source, false) {
+            Ok(_) => panic!("expected an error"),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn args_replace_params() {
+        assert_eq!(
+            expanded("macro add_to(var, n)\nvar + n -> var\nend\nadd_to!(x, 2 * y)"),
+            expanded("x + 2 * y -> x")
+        );
+    }
+
+    #[test]
+    fn definition_does_not_run() {
+        assert_eq!(
+            expanded("macro m()\nprint(1)\nend\nprint(2)"),
+            expanded("print(2)")
+        );
+    }
+
+    #[test]
+    fn labels_are_renamed_for_each_expansion() {
+        let ir = expanded("macro spin()\nagain:\ngoto again\nend\nspin!()\nspin!()");
+        let labels: Vec<_> = ir.lines().filter(|line| line.ends_with(':')).collect();
+        assert_eq!(labels.len(), 2);
+        assert_ne!(labels[0], labels[1]);
+    }
+
+    #[test]
+    fn assigned_params_need_a_variable() {
+        assert!(matches!(
+            expand_error("macro set(var)\n1 -> var\nend\nset!(1 + 2)"),
+            Error::MacroArgumentNotVariable { param, .. } if param == "var"
+        ));
+    }
+
+    #[test]
+    fn wrong_number_of_args() {
+        assert!(matches!(
+            expand_error("macro m(a)\nprint(a)\nend\nm!(1, 2)"),
+            Error::MacroArgumentMismatch {
+                expected: 1,
+                got: 2,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn recursion_is_an_error() {
+        assert!(matches!(
+            expand_error("macro m()\nm!()\nend\nm!()"),
+            Error::MacroRecursionLimit(name) if name == "m"
+        ));
+    }
+}
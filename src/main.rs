@@ -3,14 +3,24 @@
 #[allow(warnings)]
 mod grammar;
 
+mod arrays;
 mod ast;
+mod constants;
 mod error;
 mod graphics;
 mod intermediate_repr;
 mod interpreter;
+mod loader;
+mod macros;
+mod optimize;
+mod stdlib;
+mod structs;
 mod util;
 
-use std::fs::read_to_string;
+use ast::{Block, DataBlock, Section};
+use error::IResult;
+use graphics::{FramebufferFormat, GraphicsConfig, PALETTE_COLORS};
+use intermediate_repr::{DataSegment, IntermediateBlock};
 use std::path::PathBuf;
 use structopt::clap::arg_enum;
 use structopt::StructOpt;
@@ -22,6 +32,7 @@ arg_enum! {
         PrettyAst,
         DataAst,
         Ir,
+        OptIr,
         Run
     }
 }
@@ -34,14 +45,61 @@ struct Opt {
 
     #[structopt(short = "t", long = "output-type", possible_values = &OutputType::variants(), case_insensitive = true, default_value = "run")]
     output_type: OutputType,
+
+    /// Pre-size memory to a fixed number of bytes, with heap and stack regions (eg. 64K)
+    #[structopt(short = "m", long = "memory", parse(try_from_str = interpreter::memory::parse_size))]
+    memory: Option<usize>,
+
+    /// Size of the stack region when memory is fixed size
+    #[structopt(long = "stack-size", parse(try_from_str = interpreter::memory::parse_size), default_value = "4K")]
+    stack_size: usize,
+
+    /// Require every variable to be declared with `var` (data labels and function parameters count as declared)
+    #[structopt(long = "no-implicit-vars")]
+    no_implicit_vars: bool,
+
+    /// Run the IR as it is, without optimising it
+    #[structopt(long = "no-optimize")]
+    no_optimize: bool,
+
+    /// Check that every array index is less than the length of the array while the program runs
+    #[structopt(long = "bounds-checks")]
+    bounds_checks: bool,
+
+    /// Width of the screen in pixels
+    #[structopt(long = "width", default_value = "96")]
+    width: u32,
+
+    /// Height of the screen in pixels
+    #[structopt(long = "height", default_value = "64")]
+    height: u32,
+
+    /// Width and height of each pixel on the real screen
+    #[structopt(long = "scale", default_value = "8")]
+    scale: u32,
+
+    /// Window title [default: the name of the input file]
+    #[structopt(long = "title")]
+    title: Option<String>,
+
+    /// Map a framebuffer into memory, which is drawn over the screen on every present
+    #[structopt(long = "framebuffer", possible_values = &FramebufferFormat::variants(), case_insensitive = true)]
+    framebuffer: Option<FramebufferFormat>,
+
+    /// Use palette indices instead of colors when drawing, and a 256 color palette in memory
+    #[structopt(long = "palette")]
+    palette: bool,
 }
 
 fn main() -> Result<(), String> {
     let opt = Opt::from_args();
 
-    let program =
-        read_to_string(opt.input_file).map_err(|_| String::from("Could not read file"))?;
-    let (data_ast, ast) = grammar::program(&program).map_err(|e| e.to_string())?;
+    let sources = loader::load_sources(&opt.input_file).map_err(|e| e.to_string())?;
+    let mut sections = Vec::new();
+    for source in &sources {
+        sections.extend(source.parse().map_err(|e| e.to_string())?);
+    }
+    let (data_ast, ast) = split_sections(sections);
     match opt.output_type {
         OutputType::Ast => {
             println!("{:?}", ast);
@@ -59,18 +117,109 @@ fn main() -> Result<(), String> {
         }
 
         OutputType::Ir => {
-            let ir = intermediate_repr::to_intermediate_repr(ast);
+            let (ir, _) = lower(data_ast, ast, opt.bounds_checks).map_err(|e| e.to_string())?;
+            println!("{}", intermediate_repr::display_intermediate_block(&ir));
+            Ok(())
+        }
+
+        OutputType::OptIr => {
+            let (ir, _) = lower(data_ast, ast, opt.bounds_checks).map_err(|e| e.to_string())?;
+            let ir = optimize::optimize(ir);
             println!("{}", intermediate_repr::display_intermediate_block(&ir));
             Ok(())
         }
 
         OutputType::Run => {
-            let ir = intermediate_repr::to_intermediate_repr(ast);
-            let data_segment = intermediate_repr::convert_data_segment(data_ast);
-            let program = interpreter::Program::try_new(ir, data_segment).map_err(|e| e.to_string())?;
+            let (ir, data_segment) =
+                lower(data_ast, ast, opt.bounds_checks).map_err(|e| e.to_string())?;
+            let ir = if opt.no_optimize {
+                ir
+            } else {
+                optimize::optimize(ir)
+            };
+            let (mut palette_size, framebuffer_size) = match opt.framebuffer {
+                Some(format) => format.memory_sizes(opt.width, opt.height),
+                None => (0, 0),
+            };
+            if opt.palette {
+                // shared with an indexed framebuffer
+                palette_size = PALETTE_COLORS * 4;
+            }
+            let memory_config = interpreter::memory::MemoryConfig {
+                size: opt.memory,
+                stack_size: opt.stack_size,
+                palette_size,
+                framebuffer_size,
+            };
+            let program = interpreter::Program::try_new(
+                ir,
+                data_segment,
+                &memory_config,
+                !opt.no_implicit_vars,
+            )
+            .map_err(|e| e.to_string())?;
+            for warning in &program.warnings {
+                eprintln!("warning: {}", warning);
+            }
 
-            interpreter::execute(&program).map_err(|e| e.to_string())?;
+            let graphics_config = GraphicsConfig {
+                width: opt.width,
+                height: opt.height,
+                scale: opt.scale,
+                title: opt.title.clone().unwrap_or_else(|| {
+                    opt.input_file
+                        .file_name()
+                        .map_or_else(String::new, |name| name.to_string_lossy().into_owned())
+                }),
+                framebuffer: opt.framebuffer,
+                palette_mode: opt.palette,
+            };
+            interpreter::execute(&program, &graphics_config).map_err(|e| e.to_string())?;
             Ok(())
         }
     }
 }
+
+/// Separates the data blocks of a program from its code.
+/// All code sections are joined in order, as if they were one section.
+fn split_sections(sections: Vec<Section<'_>>) -> (Vec<DataBlock<'_>>, Block<'_>) {
+    let mut data_blocks = Vec::new();
+    let mut code = Vec::new();
+    for section in sections {
+        match section {
+            Section::Data(data) => data_blocks.push(data),
+            Section::Code(lines) => code.extend(lines),
+            // the loader has already added the included files' sections
+            Section::Include(_) | Section::Import(_) => {}
+        }
+    }
+    (data_blocks, code)
+}
+
+/// Converts the parsed program to IR and a data segment, with all constants substituted
+fn lower<'a>(
+    data_ast: Vec<DataBlock<'a>>,
+    ast: Block<'a>,
+    bounds_checks: bool,
+) -> IResult<(IntermediateBlock<'a>, DataSegment<'a>)> {
+    let ir = intermediate_repr::to_intermediate_repr(ast)?;
+    let structs = structs::collect_structs(&data_ast, &ir)?;
+    let constants = constants::collect_constants(&data_ast, &ir, &structs)?;
+    let ir = constants::substitute_constants(ir, &constants)?;
+    let ir = structs::lower_field_access(ir, &structs)?;
+    let arrays = arrays::collect_arrays(&data_ast, &constants);
+    let ir = arrays::lower_indexing(ir, &arrays, bounds_checks)?;
+    let data_segment = intermediate_repr::convert_data_segment(data_ast, &constants)?;
+    Ok((ir, data_segment))
+}
+
+/// Parses a program from a string and lowers it, for tests of the lowering passes
+#[cfg(test)]
+fn lower_source(
+    source: &str,
+    bounds_checks: bool,
+) -> IResult<(IntermediateBlock<'_>, DataSegment<'_>)> {
+    let sections = grammar::program(source).expect("test program should parse");
+    let (data_ast, ast) = split_sections(sections);
+    lower(data_ast, ast, bounds_checks)
+}
@@ -0,0 +1,207 @@
+//! Optimisation passes over the IR.
+//! Folds constant expressions, simplifies jumps on constant conditions,
+//! and removes unreachable lines and unused internal labels.
+
+use crate::ast::{AssignTarget, Expr};
+use crate::constants::{eval_const_expr, ConstTable};
+use crate::intermediate_repr::{IntermediateBlock, IntermediateLine, INTERNAL_LABEL_PREFIX};
+
+use std::collections::HashSet;
+
+/// Runs all optimisation passes until the block stops shrinking
+pub fn optimize(block: IntermediateBlock<'_>) -> IntermediateBlock<'_> {
+    let mut block = simplify_jumps(fold_constants(block));
+    loop {
+        let len = block.len();
+        block = remove_unused_labels(remove_unreachable(block));
+        if block.len() == len {
+            return block;
+        }
+    }
+}
+
+/// Replaces every subexpression that only uses literals with its value
+fn fold_constants(mut block: IntermediateBlock<'_>) -> IntermediateBlock<'_> {
+    let no_constants = ConstTable::new();
+    // children are folded before their parents, so folding works from the leaves up
+    let fold = &mut |e: &mut Expr| {
+        if let Expr::Literal(_) = e {
+            return;
+        }
+        // anything that is not made of literals (or would error, eg. divide by 0) is left for runtime
+        if let Ok(value) = eval_const_expr(e, &no_constants) {
+            *e = Expr::Literal(value);
+        }
+    };
+
+    for line in &mut block {
+        match line {
+            IntermediateLine::Assign(target, e) => {
+                e.visit_mut(fold);
+                if let AssignTarget::Addr(addr) | AssignTarget::ByteAddr(addr) = target {
+                    addr.visit_mut(fold);
+                }
+            }
+            IntermediateLine::JumpFalse(e, _) | IntermediateLine::Expr(e) => e.visit_mut(fold),
+            _ => {}
+        }
+    }
+    block
+}
+
+/// Turns jumps on a constant false condition into gotos, and removes jumps on a constant true condition
+fn simplify_jumps(block: IntermediateBlock<'_>) -> IntermediateBlock<'_> {
+    block
+        .into_iter()
+        .filter_map(|line| match line {
+            IntermediateLine::JumpFalse(Expr::Literal(0), label) => {
+                Some(IntermediateLine::Goto(label))
+            }
+            IntermediateLine::JumpFalse(Expr::Literal(_), _) => None,
+            line => Some(line),
+        })
+        .collect()
+}
+
+/// Removes lines after a goto that can never be reached
+/// (until the next label, function declaration or function return),
+/// and gotos that jump to the very next line.
+/// Variable declarations are kept, as they do not run but are needed to allocate variables
+fn remove_unreachable(block: IntermediateBlock<'_>) -> IntermediateBlock<'_> {
+    let mut optimized: IntermediateBlock = Vec::with_capacity(block.len());
+    let mut reachable = true;
+    for line in block {
+        match &line {
+            IntermediateLine::Label(label) => {
+                reachable = true;
+                // the goto may be followed by declarations, which are skipped over
+                let last_run = optimized
+                    .iter()
+                    .rposition(|line| !matches!(line, IntermediateLine::VarDeclaration(_)));
+                if let Some(index) = last_run {
+                    if let IntermediateLine::Goto(target) = &optimized[index] {
+                        if target == label {
+                            optimized.remove(index);
+                        }
+                    }
+                }
+            }
+            // functions are entered by calls, and FunReturn must stay for calls to finish
+            IntermediateLine::FunDeclaration(..) | IntermediateLine::FunReturn => reachable = true,
+            IntermediateLine::VarDeclaration(_) => {
+                optimized.push(line);
+                continue;
+            }
+            _ => {}
+        }
+
+        if reachable {
+            if let IntermediateLine::Goto(_) = line {
+                reachable = false;
+            }
+            optimized.push(line);
+        }
+    }
+    optimized
+}
+
+/// Removes internal labels (generated by control structures) that are never jumped to
+fn remove_unused_labels(block: IntermediateBlock<'_>) -> IntermediateBlock<'_> {
+    let used: HashSet<String> = block
+        .iter()
+        .filter_map(|line| match line {
+            IntermediateLine::Goto(label) | IntermediateLine::JumpFalse(_, label) => {
+                Some(label.to_string())
+            }
+            _ => None,
+        })
+        .collect();
+
+    block
+        .into_iter()
+        .filter(|line| match line {
+            IntermediateLine::Label(label) => {
+                !label.starts_with(INTERNAL_LABEL_PREFIX) || used.contains(label.as_ref())
+            }
+            _ => true,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::optimize;
+    use crate::ast::Section;
+    use crate::grammar;
+    use crate::intermediate_repr::{display_intermediate_block, to_intermediate_repr};
+
+    /// Optimises the code of a program, and returns the optimised IR as `-t OptIr` prints it
+    fn optimized(code: &str) -> String {
+        let source = format!(".DATA\nd:\n0\n.CODE\n{}", code);
+        let lines = grammar::program(&source)
+            .unwrap()
+            .into_iter()
+            .flat_map(|section| match section {
+                Section::Code(lines) => lines,
+                _ => Vec::new(),
+            })
+            .collect();
+        display_intermediate_block(&optimize(to_intermediate_repr(lines).unwrap()))
+    }
+
+    #[test]
+    fn folds_constant_subexpressions() {
+        assert_eq!(
+            optimized("x + 2 * 3 -> y"),
+            "Add(Var(\"x\"), Literal(6)) -> Var(\"y\")\n"
+        );
+    }
+
+    #[test]
+    fn folding_wraps_like_the_interpreter() {
+        assert_eq!(
+            optimized("0x80000000 * 2 -> a\n1 << 33 -> b\n0 - 1 -> c"),
+            "Literal(0) -> Var(\"a\")\nLiteral(2) -> Var(\"b\")\nLiteral(4294967295) -> Var(\"c\")\n"
+        );
+    }
+
+    #[test]
+    fn division_by_zero_is_left_for_runtime() {
+        assert_eq!(
+            optimized("1 / 0 -> a"),
+            "Div(Literal(1), Literal(0)) -> Var(\"a\")\n"
+        );
+    }
+
+    #[test]
+    fn removes_jumps_on_constant_conditions() {
+        assert_eq!(
+            optimized("if 0\nprint(1)\nend\nwhile 1\nprint(2)\nend"),
+            "$internal_1:\nFunCall(\"print\", [Literal(2)])\ngoto $internal_1\n"
+        );
+    }
+
+    #[test]
+    fn removes_unreachable_lines() {
+        assert_eq!(
+            optimized("goto skip\nprint(1)\nskip:\nprint(2)"),
+            "skip:\nFunCall(\"print\", [Literal(2)])\n"
+        );
+    }
+
+    #[test]
+    fn keeps_unreachable_var_declarations() {
+        assert_eq!(
+            optimized("goto start\nvar counter\nstart:\ncounter + 1 -> counter"),
+            "var counter\nstart:\nAdd(Var(\"counter\"), Literal(1)) -> Var(\"counter\")\n"
+        );
+    }
+
+    #[test]
+    fn keeps_functions_after_goto() {
+        assert_eq!(
+            optimized("goto skip\nprint(0)\nfunc f()\nprint(1)\nend\nskip:"),
+            "goto skip\nfunc f[]\nFunCall(\"print\", [Literal(1)])\nreturn\nskip:\n"
+        );
+    }
+}
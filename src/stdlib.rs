@@ -0,0 +1,31 @@
+//! The standard library: BrownCode modules embedded in the interpreter, which programs can use with `import NAME`
+
+/// Name and source of each library
+const LIBRARIES: &[(&str, &str)] = &[
+    ("math", include_str!("../stdlib/math.brown")),
+    ("string", include_str!("../stdlib/string.brown")),
+    ("mem", include_str!("../stdlib/mem.brown")),
+];
+
+/// Returns the source of the library with the given name
+pub fn library_source(name: &str) -> Option<&'static str> {
+    LIBRARIES
+        .iter()
+        .find(|(library, _)| *library == name)
+        .map(|(_, source)| *source)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LIBRARIES;
+    use crate::lower_source;
+
+    #[test]
+    fn libraries_parse_and_lower() {
+        for (name, source) in LIBRARIES {
+            if let Err(e) = lower_source(source, true) {
+                panic!("library `{}` does not load: {}", name, e);
+            }
+        }
+    }
+}
@@ -0,0 +1,221 @@
+//! Structs (`struct NAME ... end`) describe the layout of records in memory.
+//! Each struct defines constants for the offset of each of its fields (`NAME.field`) and its size (`NAME.size`).
+//! `var.field` reads or writes a field of the record that the variable var points to.
+
+use crate::ast::{AssignTarget, DataBlock, DataDef, Expr, FieldWidth, StructField};
+use crate::error::{Error, IResult};
+use crate::intermediate_repr::{IntermediateBlock, IntermediateBlockSlice, IntermediateLine};
+
+use std::collections::HashMap;
+
+/// Name of the constant for the size of a struct, so no field can have this name
+const SIZE_NAME: &str = "size";
+
+/// Fields are stored in order, with no padding between them
+struct StructLayout<'a> {
+    /// name, width and offset of each field
+    fields: Vec<(&'a str, FieldWidth, u32)>,
+    size: u32,
+}
+
+/// Maps struct names to their layouts
+pub struct StructTable<'a>(HashMap<&'a str, StructLayout<'a>>);
+
+/// Finds every struct declared in the data sections and the code
+pub fn collect_structs<'a>(
+    data: &[DataBlock<'a>],
+    code: &IntermediateBlockSlice<'a>,
+) -> IResult<StructTable<'a>> {
+    let data_structs = data.iter().flatten().filter_map(|data_def| match data_def {
+        DataDef::Struct(name, fields) => Some((*name, fields)),
+        _ => None,
+    });
+    let code_structs = code.iter().filter_map(|line| match line {
+        IntermediateLine::Struct(name, fields) => Some((*name, fields)),
+        _ => None,
+    });
+
+    let mut structs = HashMap::new();
+    for (name, fields) in data_structs.chain(code_structs) {
+        let layout = StructLayout::try_new(name, fields)?;
+        if structs.insert(name, layout).is_some() {
+            return Err(Error::StructRedefinition(name.to_string()));
+        }
+    }
+    Ok(StructTable(structs))
+}
+
+impl<'a> StructLayout<'a> {
+    fn try_new(struct_name: &str, fields: &[StructField<'a>]) -> IResult<Self> {
+        let mut layout = StructLayout {
+            fields: Vec::with_capacity(fields.len()),
+            size: 0,
+        };
+        for field in fields {
+            if field.name == SIZE_NAME || layout.fields.iter().any(|f| f.0 == field.name) {
+                return Err(Error::FieldRedefinition {
+                    struct_name: struct_name.to_string(),
+                    field: field.name.to_string(),
+                });
+            }
+            layout.fields.push((field.name, field.width, layout.size));
+            layout.size += field.width.size();
+        }
+        Ok(layout)
+    }
+}
+
+impl<'a> StructTable<'a> {
+    /// The offset constant of every field and the size constant of every struct
+    pub fn constants(&self) -> Vec<(String, u32)> {
+        let mut constants = Vec::new();
+        for (name, layout) in &self.0 {
+            constants.push((format!("{}.{}", name, SIZE_NAME), layout.size));
+            for (field, _, offset) in &layout.fields {
+                constants.push((format!("{}.{}", name, field), *offset));
+            }
+        }
+        constants
+    }
+
+    /// Finds the width and offset of a field from the struct that has it.
+    /// Several structs may have a field with the same name, as long as it is at the same offset with the same width.
+    fn find_field(&self, field: &str) -> IResult<(FieldWidth, u32)> {
+        let mut found = self.0.values().flat_map(|layout| {
+            layout
+                .fields
+                .iter()
+                .filter(|f| f.0 == field)
+                .map(|f| (f.1, f.2))
+        });
+        let first = found
+            .next()
+            .ok_or_else(|| Error::FieldNotFound(field.to_string()))?;
+        if found.any(|other| other != first) {
+            return Err(Error::AmbiguousField(field.to_string()));
+        }
+        Ok(first)
+    }
+
+    /// The address of a field of the struct that the variable base points to
+    fn field_address<'b>(&self, base: &'b str, field: &str) -> IResult<(FieldWidth, Expr<'b>)> {
+        let (width, offset) = self.find_field(field)?;
+        let address = Expr::Add(Box::new(Expr::Var(base)), Box::new(Expr::Literal(offset)));
+        Ok((width, address))
+    }
+}
+
+/// Replaces every field access with a memory access of the field's width, and removes the struct declarations.
+/// Must run after constants are substituted, so `NAME.field` constants are no longer field accesses.
+pub fn lower_field_access<'a>(
+    code: IntermediateBlock<'a>,
+    structs: &StructTable<'a>,
+) -> IResult<IntermediateBlock<'a>> {
+    let lower = &mut |e: &mut Expr<'a>| {
+        if let Expr::Field(base, field) = e {
+            let (width, address) = structs.field_address(base, field)?;
+            *e = match width {
+                FieldWidth::U8 => Expr::DerefByte(Box::new(address)),
+                FieldWidth::U32 => Expr::Deref(Box::new(address)),
+            };
+        }
+        Ok(())
+    };
+
+    let mut block = Vec::with_capacity(code.len());
+    for mut line in code {
+        match &mut line {
+            IntermediateLine::Struct(..) => continue,
+            IntermediateLine::Assign(target, e) => {
+                e.try_visit_mut(lower)?;
+                match target {
+                    AssignTarget::Field(base, field) => {
+                        let (width, address) = structs.field_address(base, field)?;
+                        *target = match width {
+                            FieldWidth::U8 => AssignTarget::ByteAddr(address),
+                            FieldWidth::U32 => AssignTarget::Addr(address),
+                        };
+                    }
+                    AssignTarget::Addr(addr)
+                    | AssignTarget::ByteAddr(addr)
+                    | AssignTarget::Index(_, addr) => addr.try_visit_mut(lower)?,
+                    AssignTarget::Var(_) => {}
+                }
+            }
+            IntermediateLine::JumpFalse(e, _) | IntermediateLine::Expr(e) => {
+                e.try_visit_mut(lower)?
+            }
+            _ => {}
+        }
+        block.push(line);
+    }
+    Ok(block)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::intermediate_repr::display_intermediate_block;
+    use crate::lower_source;
+
+    const PLAYER: &str = ".DATA\nstruct Player\nx: u32\ny: u32\nlives: u8\nend\n.CODE\n";
+
+    /// The IR of code that can use the Player struct
+    fn lowered(code: &str) -> String {
+        let source = format!("{}{}", PLAYER, code);
+        let (ir, _) = lower_source(&source, false).unwrap();
+        display_intermediate_block(&ir)
+    }
+
+    fn lower_error(source: &str) -> Error {
+        match lower_source(source, false) {
+            Ok(_) => panic!("expected an error"),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn offsets_and_size_are_constants() {
+        assert_eq!(
+            lowered("numprintln(Player.x)\nnumprintln(Player.lives)\nnumprintln(Player.size)"),
+            lowered("numprintln(0)\nnumprintln(8)\nnumprintln(9)")
+        );
+    }
+
+    #[test]
+    fn fields_are_memory_accesses_of_their_width() {
+        assert_eq!(
+            lowered("p.y + 1 -> p.lives"),
+            lowered("{p + 4} + 1 -> [p + 8]")
+        );
+    }
+
+    #[test]
+    fn fields_with_different_layouts_are_ambiguous() {
+        let source = format!("{}struct Enemy\nlives: u32\nend\n1 -> e.lives", PLAYER);
+        assert!(matches!(lower_error(&source), Error::AmbiguousField(field) if field == "lives"));
+    }
+
+    #[test]
+    fn shared_fields_with_the_same_layout() {
+        assert_eq!(
+            lowered("struct Point\nx: u32\nend\n1 -> p.x"),
+            lowered("1 -> {p + 0}")
+        );
+    }
+
+    #[test]
+    fn field_can_not_be_named_size() {
+        assert!(matches!(
+            lower_error(".DATA\nstruct S\nsize: u32\nend\n.CODE\nexit()"),
+            Error::FieldRedefinition { field, .. } if field == "size"
+        ));
+    }
+
+    #[test]
+    fn unknown_field() {
+        assert!(
+            matches!(lower_error(&format!("{}p.z -> a", PLAYER)), Error::FieldNotFound(field) if field == "z")
+        );
+    }
+}
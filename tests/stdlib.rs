@@ -0,0 +1,21 @@
+//! Runs the standard library's tests (stdlib/tests.brown) without a display
+
+use std::process::Command;
+
+#[test]
+fn stdlib_tests_pass() {
+    let output = Command::new(env!("CARGO_BIN_EXE_browncode"))
+        .args(["--memory", "8K", "stdlib/tests.brown"])
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .env("SDL_VIDEODRIVER", "dummy")
+        .output()
+        .expect("browncode should start");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "browncode failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(!stdout.contains("FAILED"), "{}", stdout);
+    assert!(stdout.contains("all tests passed"), "{}", stdout);
+}